@@ -0,0 +1,203 @@
+//
+//  Copyright (C) 2021 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record, log};
+use serde_json::{Map, Value as JsonValue};
+use simplelog::{Config, SharedLogger};
+use std::fmt;
+use std::io::{Write, stdin};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// Kinds of authentication events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    AttemptStarted,
+    FactorPrompted,
+    FactorAccepted,
+    FactorRejected,
+    FactorSkipped,
+    LockedOut,
+    ShellExec,
+}
+
+impl Event {
+    /// Name of the event in JSON records
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::AttemptStarted => "attempt_started",
+            Self::FactorPrompted => "factor_prompted",
+            Self::FactorAccepted => "factor_accepted",
+            Self::FactorRejected => "factor_rejected",
+            Self::FactorSkipped => "factor_skipped",
+            Self::LockedOut => "locked_out",
+            Self::ShellExec => "shell_exec",
+        }
+    }
+}
+
+/// What identifies the login, included in every JSON record
+#[derive(Debug)]
+struct Session {
+    /// Random ID correlating the records of one login
+    id: String,
+    user: String,
+    ip: String,
+    tty: Option<String>,
+    pid: u32,
+}
+
+static SESSION: OnceLock<Session> = OnceLock::new();
+
+/// Record who is logging in from where
+pub fn start_session(user: &str, ip: &str) {
+    let id: u64 = rand::random();
+    let session = Session {
+        id: format!("{id:016x}"),
+        user: user.to_string(),
+        ip: ip.to_string(),
+        tty: nix::unistd::ttyname(stdin())
+            .ok()
+            .map(|tty| tty.display().to_string()),
+        pid: process::id(),
+    };
+    SESSION.set(session).ok();
+}
+
+/// Log an audit event, concerning `authenticator` if any
+pub fn event(level: Level, event: Event, authenticator: Option<&str>, message: fmt::Arguments) {
+    let event = event.as_str();
+    match authenticator {
+        Some(authenticator) => log!(level, event, authenticator; "{message}"),
+        None => log!(level, event; "{message}"),
+    }
+}
+
+/// Collects the key-value pairs of a record
+struct Fields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0
+            .insert(key.to_string(), JsonValue::String(value.to_string()));
+        Ok(())
+    }
+}
+
+/// All fields of a record as logged in JSON, including the session
+pub fn record_fields(record: &Record) -> Map<String, JsonValue> {
+    let mut fields = Map::new();
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    fields.insert(String::from("timestamp"), JsonValue::String(timestamp));
+    fields.insert(
+        String::from("level"),
+        JsonValue::String(record.level().to_string()),
+    );
+    // Records that are not audit events
+    fields.insert(
+        String::from("event"),
+        JsonValue::String(String::from("log")),
+    );
+    record.key_values().visit(&mut Fields(&mut fields)).ok();
+    fields.insert(
+        String::from("message"),
+        JsonValue::String(record.args().to_string()),
+    );
+    if let Some(session) = SESSION.get() {
+        fields.insert(
+            String::from("session"),
+            JsonValue::String(session.id.clone()),
+        );
+        fields.insert(
+            String::from("user"),
+            JsonValue::String(session.user.clone()),
+        );
+        fields.insert(String::from("ip"), JsonValue::String(session.ip.clone()));
+        fields.insert(
+            String::from("tty"),
+            session
+                .tty
+                .clone()
+                .map_or(JsonValue::Null, JsonValue::String),
+        );
+        fields.insert(String::from("pid"), JsonValue::from(session.pid));
+    }
+    fields
+}
+
+/// Whether a record is an audit event rather than a free-form message
+pub fn is_event(record: &Record) -> bool {
+    record.key_values().get(Key::from("event")).is_some()
+}
+
+/// Logger writing every record as a JSON object on its own line
+pub struct JsonLogger<W: Write + Send> {
+    level: LevelFilter,
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLogger<W> {
+    pub fn new(level: LevelFilter, writer: W) -> Self {
+        Self {
+            level,
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send> Log for JsonLogger<W> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let fields = record_fields(record);
+        if let Ok(mut writer) = self.writer.lock() {
+            writeln!(writer, "{}", JsonValue::Object(fields)).ok();
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            writer.flush().ok();
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> SharedLogger for JsonLogger<W> {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
@@ -19,6 +19,7 @@
 
 use crate::config::SecRcCfg;
 use crate::ip::get_from;
+use crate::lockout::Lockout;
 use cidr::IpCidr;
 use log::warn;
 use std::net::IpAddr;
@@ -26,18 +27,21 @@ use std::net::IpAddr;
 /// Trait for authenticate providers
 pub trait Authenticator<'auth> {
     /// Initialize authenticator from shell configuration
-    fn init(config: &'auth SecRcCfg) -> Self;
+    fn init(config: &'auth SecRcCfg) -> Self
+    where
+        Self: Sized;
     /// Check if the login is accepted by this authenticator
     /// Some(true) is yes
     /// Some(false) is rejected
     /// None is cancelled
     /// CMD is supplied only when -c cmdline is used and contains the
     /// argument to -c
-    fn is_accepted_login(&self) -> Option<bool>;
+    /// Every wrong code is counted in `lockout`
+    fn is_accepted_login(&self, lockout: &Lockout) -> Option<bool>;
     /// Check if the execute request is accepted by this authenticator
     /// The modified (if any) command line is put back into cmd
     /// i.e. when -c cmdline is supplied
-    fn is_accepted_exec(&self, cmd: &mut String) -> Option<bool>;
+    fn is_accepted_exec(&self, cmd: &mut String, lockout: &Lockout) -> Option<bool>;
 }
 
 pub struct LocalIPAuthenticator<'a> {
@@ -49,7 +53,7 @@ impl<'a> Authenticator<'a> for LocalIPAuthenticator<'a> {
         LocalIPAuthenticator { config }
     }
 
-    fn is_accepted_login(&self) -> Option<bool> {
+    fn is_accepted_login(&self, _lockout: &Lockout) -> Option<bool> {
         let checking: IpAddr = match get_from().parse() {
             Ok(ok) => ok,
             Err(_e) => return None,
@@ -73,8 +77,8 @@ impl<'a> Authenticator<'a> for LocalIPAuthenticator<'a> {
         None
     }
 
-    fn is_accepted_exec(&self, _cmd: &mut String) -> Option<bool> {
-        self.is_accepted_login()
+    fn is_accepted_exec(&self, _cmd: &mut String, lockout: &Lockout) -> Option<bool> {
+        self.is_accepted_login(lockout)
     }
 }
 
@@ -85,7 +89,7 @@ impl Authenticator<'_> for BypassAuthenticator {
         Self {}
     }
 
-    fn is_accepted_login(&self) -> Option<bool> {
+    fn is_accepted_login(&self, _lockout: &Lockout) -> Option<bool> {
         if let Some(mut home_dir) = home::home_dir() {
             home_dir.push("NoSec");
             if home_dir.exists() {
@@ -100,7 +104,7 @@ impl Authenticator<'_> for BypassAuthenticator {
         None
     }
 
-    fn is_accepted_exec(&self, _cmd: &mut String) -> Option<bool> {
-        self.is_accepted_login()
+    fn is_accepted_exec(&self, _cmd: &mut String, lockout: &Lockout) -> Option<bool> {
+        self.is_accepted_login(lockout)
     }
 }
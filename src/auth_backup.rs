@@ -0,0 +1,219 @@
+//
+//  Copyright (C) 2026 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::auth::Authenticator;
+use crate::config::{self, FileKind, SecRcCfg};
+use crate::lockout::Lockout;
+use crate::state::{self, LockedState};
+use crate::terminal;
+use argon2::Argon2;
+use argon2::password_hash::{
+    Error as HashError, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use log::{error, info, warn};
+use rand::RngExt;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+
+/// Number of codes generated in a batch
+const NUM_CODES: usize = 10;
+/// Length of a code, not counting the separating dash
+pub const CODE_LEN: usize = 10;
+/// Lower-case alphanumeric characters, easy to type on any keyboard
+pub const CODE_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Contents of the backup codes file
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct BackupCodes {
+    /// PHC strings of the unused codes
+    #[serde(default)]
+    codes: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("`backup_codes_file` is not set")]
+    NotConfigured,
+    #[error("invalid `backup_codes_file`")]
+    InvalidPath,
+    #[error("cannot hash backup code: {0}")]
+    Hash(#[from] HashError),
+    #[error("cannot access backup codes: {0}")]
+    State(#[from] state::Error),
+    #[error("refusing backup codes: {0}")]
+    Config(#[from] config::Error),
+}
+
+pub struct BackupCodeAuthenticator<'a> {
+    config: &'a SecRcCfg,
+    enabled: bool,
+}
+
+/// Lock the backup codes file at `path`, usually in the home directory
+fn open_codes(config: &SecRcCfg, path: &str) -> Result<LockedState, Error> {
+    let path = Path::new(path);
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Err(Error::InvalidPath);
+    };
+    Ok(LockedState::open(config, dir, name, FileKind::UserConfig)?)
+}
+
+/// Remove dashes and whitespace the user may have typed
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|ch| *ch != '-' && !ch.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+impl BackupCodeAuthenticator<'_> {
+    fn codes_file(&self) -> Result<&str, Error> {
+        self.config
+            .backup_codes_file
+            .as_deref()
+            .ok_or(Error::NotConfigured)
+    }
+
+    /// Check `code` against the stored ones, consuming it if it matches
+    fn consume_code(&self, code: &str) -> Result<bool, Error> {
+        let state = open_codes(self.config, self.codes_file()?)?;
+        self.config
+            .check_secret_file(Path::new(self.codes_file()?))?;
+        let mut backup_codes: BackupCodes = state.read()?;
+        let code = normalize_code(code);
+        let argon2 = Argon2::default();
+        let matched = backup_codes.codes.iter().position(|hash| {
+            PasswordHash::new(hash)
+                .is_ok_and(|hash| argon2.verify_password(code.as_bytes(), &hash).is_ok())
+        });
+        let Some(index) = matched else {
+            return Ok(false);
+        };
+        backup_codes.codes.remove(index);
+        state.write(&backup_codes)?;
+        warn!("Backup code used, {} left", backup_codes.codes.len());
+        Ok(true)
+    }
+
+    /// Replace all stored codes with a fresh batch and print them once
+    pub fn generate(&self) -> Result<(), Error> {
+        let state = open_codes(self.config, self.codes_file()?)?;
+        let argon2 = Argon2::default();
+        let mut rng = rand::rng();
+        let mut backup_codes = BackupCodes::default();
+        let mut plain_codes: Vec<String> = Vec::with_capacity(NUM_CODES);
+        for _ in 0..NUM_CODES {
+            let code: String = (0..CODE_LEN)
+                .map(|_| char::from(CODE_CHARSET[rng.random_range(0..CODE_CHARSET.len())]))
+                .collect();
+            let salt_bytes: [u8; 16] = rng.random();
+            let salt = SaltString::encode_b64(&salt_bytes)?;
+            backup_codes
+                .codes
+                .push(argon2.hash_password(code.as_bytes(), &salt)?.to_string());
+            plain_codes.push(code);
+        }
+        // Only show the codes once they are saved
+        state.write(&backup_codes)?;
+        println!("Your new backup codes, each can be used once:");
+        for code in plain_codes {
+            println!("{}-{}", &code[..CODE_LEN / 2], &code[CODE_LEN / 2..]);
+        }
+        info!("Generated a new batch of backup codes");
+        Ok(())
+    }
+}
+
+impl<'a> Authenticator<'a> for BackupCodeAuthenticator<'a> {
+    fn init(config: &'a SecRcCfg) -> Self {
+        let enabled = config
+            .backup_codes_file
+            .as_ref()
+            .is_some_and(|path| Path::new(path).exists());
+        BackupCodeAuthenticator { config, enabled }
+    }
+
+    fn is_accepted_login(&self, lockout: &Lockout) -> Option<bool> {
+        if !self.enabled {
+            return None;
+        }
+        let mut tries: u8 = 0;
+        while tries < 3 {
+            tries += 1;
+            let attempt = lockout.begin_attempt();
+            let input = match terminal::read_hidden("Enter a backup code: ") {
+                Ok(input) => input,
+                Err(error) => {
+                    // Most likely a disconnection, which still counts
+                    error!("{error}");
+                    return None;
+                }
+            };
+            let input = input.trim();
+            if input.is_empty() {
+                // Skip this authenticator
+                attempt.forget();
+                return None;
+            }
+            match self.consume_code(input) {
+                Ok(true) => {
+                    attempt.forget();
+                    return Some(true);
+                }
+                Ok(false) => warn!("Wrong backup code"),
+                Err(error) => {
+                    attempt.forget();
+                    error!("{error}");
+                    return None;
+                }
+            }
+        }
+        // Maximum number of tries exceeded
+        error!("Maximum number of retries exceeded");
+        Some(false)
+    }
+
+    fn is_accepted_exec(&self, cmd: &mut String, lockout: &Lockout) -> Option<bool> {
+        if !self.enabled || cmd.len() < CODE_LEN || !cmd.is_char_boundary(CODE_LEN) {
+            return None;
+        }
+        let input = &cmd[0..CODE_LEN];
+        // Not a backup code but probably a command
+        if !input.bytes().all(|byte| CODE_CHARSET.contains(&byte)) {
+            return None;
+        }
+        let attempt = lockout.begin_attempt();
+        match self.consume_code(input) {
+            Ok(true) => {
+                attempt.forget();
+                // Remove the code
+                *cmd = cmd[CODE_LEN..cmd.len()].to_string();
+                Some(true)
+            }
+            // Still counted, but the next authenticator may accept it
+            Ok(false) => None,
+            Err(error) => {
+                attempt.forget();
+                error!("{error}");
+                None
+            }
+        }
+    }
+}
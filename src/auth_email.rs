@@ -18,7 +18,11 @@
 //
 
 use crate::auth::Authenticator;
+use crate::auth_totp::is_code;
 use crate::config::SecRcCfg;
+use crate::lockout::Lockout;
+use crate::secret::Redacted;
+use crate::terminal;
 use lettre::transport::smtp::{
     Error as SmtpError,
     authentication::Credentials,
@@ -29,11 +33,14 @@ use lettre::{
 };
 use log::{debug, error, info, warn};
 use std::fs::{File, remove_file};
-use std::io::{Read, Write, stdin, stdout};
+use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use thiserror::Error;
 
+/// Number of digits of a code
+pub const CODE_LEN: usize = 6;
+
 pub struct EmailAuthenticator<'a> {
     config: &'a SecRcCfg,
     enabled: bool,
@@ -63,12 +70,11 @@ impl EmailAuthenticator<'_> {
 
     fn read_password(&self) -> Result<String, Error> {
         if let Some(passwdcmd) = &self.config.mail_passwdcmd {
-            let mut args = passwdcmd.split_whitespace();
-            let cmd = args.next().ok_or(Error::InvalidPasswdCmd)?;
-            let cmd_args: Vec<String> = args.map(ToString::to_string).collect();
+            let args = passwdcmd.to_args().map_err(|_| Error::InvalidPasswdCmd)?;
+            let (cmd, cmd_args) = args.split_first().ok_or(Error::InvalidPasswdCmd)?;
 
             let output = Command::new(cmd)
-                .args(&cmd_args)
+                .args(cmd_args)
                 .stdout(Stdio::piped())
                 .output()?
                 .stdout;
@@ -148,7 +154,7 @@ impl<'a> Authenticator<'a> for EmailAuthenticator<'a> {
         }
     }
 
-    fn is_accepted_login(&self) -> Option<bool> {
+    fn is_accepted_login(&self, lockout: &Lockout) -> Option<bool> {
         if !self.enabled {
             return None;
         }
@@ -166,16 +172,17 @@ impl<'a> Authenticator<'a> for EmailAuthenticator<'a> {
         let mut tries: u8 = 0;
 
         // First ask the user for email
-        let stdin = stdin();
         while tries < 3 {
-            let mut input = String::new();
             tries += 1;
-            print!("Enter your email matching {shadowemail}: ");
-            stdout().flush().ok();
-            if let Err(error) = stdin.read_line(&mut input) {
-                error!("{error}");
-                return None;
-            }
+            let mut input = match terminal::read_hidden(&format!(
+                "Enter your email matching {shadowemail}: "
+            )) {
+                Ok(input) => input,
+                Err(error) => {
+                    error!("{error}");
+                    return None;
+                }
+            };
             input = input.trim_end().to_string();
             if input.is_empty() {
                 // Skip this authenticator
@@ -185,7 +192,7 @@ impl<'a> Authenticator<'a> for EmailAuthenticator<'a> {
                 tries = 0;
                 break;
             }
-            warn!("Wrong email {input:?}");
+            warn!("Wrong email {:?}", Redacted(&input));
         }
         if tries != 0 {
             // Maximum number of tries exceeded
@@ -197,27 +204,33 @@ impl<'a> Authenticator<'a> for EmailAuthenticator<'a> {
             return None;
         }
         while tries < 3 {
-            let mut input = String::new();
             tries += 1;
-            print!("Enter the code sent to your email address, 0 to resend: ");
-            stdout().flush().ok();
-            if let Err(error) = stdin.read_line(&mut input) {
-                error!("{error}");
-                return None;
-            }
+            let attempt = lockout.begin_attempt();
+            let input = match terminal::read_hidden(
+                "Enter the code sent to your email address, 0 to resend: ",
+            ) {
+                Ok(input) => input,
+                Err(error) => {
+                    // Most likely a disconnection, which still counts
+                    error!("{error}");
+                    return None;
+                }
+            };
             let input = input.trim_end().parse();
             if input == Ok(0) {
                 // Not counting this one
                 tries -= 1;
+                attempt.forget();
                 if let Err(error) = self.send_email("") {
                     error!("{error}");
                     return None;
                 }
             } else if Ok(self.code) == input {
+                attempt.forget();
                 return Some(true);
             } else {
                 // Not 0 nor matched
-                warn!("Wrong login code {input:?}");
+                warn!("Wrong login code {:?}", Redacted(input));
             }
         }
         // Maximum number of tries exceeded
@@ -225,7 +238,7 @@ impl<'a> Authenticator<'a> for EmailAuthenticator<'a> {
         Some(false)
     }
 
-    fn is_accepted_exec(&self, cmd: &mut String) -> Option<bool> {
+    fn is_accepted_exec(&self, cmd: &mut String, lockout: &Lockout) -> Option<bool> {
         let mut sib_code_file = PathBuf::from(
             &self
                 .config
@@ -248,7 +261,7 @@ impl<'a> Authenticator<'a> for EmailAuthenticator<'a> {
                 // This is certainly unwanted
                 Err(e) => error!("Create code file failed: {e}"),
             }
-            // Cancel execution
+            // Cancel execution, which is not a failed attempt
             return Some(false);
         }
         match File::open(&sib_code_file) {
@@ -257,13 +270,21 @@ impl<'a> Authenticator<'a> for EmailAuthenticator<'a> {
                 file.read_to_string(&mut code).ok()?;
                 code = code.trim().to_string();
                 // If cmd is shorter that 6 chars it's always bad
-                if cmd.len() >= 6 && cmd[0..6] == code {
+                if cmd.get(0..CODE_LEN) == Some(code.as_str()) {
                     // Remove the code from cmd
-                    *cmd = cmd[6..cmd.len()].to_string();
+                    *cmd = cmd[CODE_LEN..cmd.len()].to_string();
                     remove_file(&sib_code_file).ok();
                     Some(true)
                 } else {
-                    warn!("Read {:?} from code file, found {:?}", code, &cmd[0..6]);
+                    if cmd.get(0..CODE_LEN).is_some_and(is_code) {
+                        // Still counted, but the next authenticator may accept it
+                        drop(lockout.begin_attempt());
+                    }
+                    warn!(
+                        "Read {:?} from code file, found {:?}",
+                        Redacted(&code),
+                        Redacted(cmd.get(0..CODE_LEN))
+                    );
                     None
                 }
             }
@@ -0,0 +1,255 @@
+//
+//  Copyright (C) 2026 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::auth::Authenticator;
+use crate::auth_totp::{is_code, parse_hash};
+use crate::config::SecRcCfg;
+use crate::lockout::Lockout;
+use crate::secret::Redacted;
+use crate::state::{self, LockedState};
+use crate::terminal;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const STATE_FILE: &str = "hotp.toml";
+
+/// Persistent state to keep track of the token counter
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct HotpState {
+    /// Next expected counter of each user
+    #[serde(default)]
+    next_counters: BTreeMap<String, u64>,
+}
+
+pub struct HotpAuthenticator<'a> {
+    config: &'a SecRcCfg,
+    enabled: bool,
+    hashtype: Algorithm,
+}
+
+impl HotpAuthenticator<'_> {
+    /// Compares an HOTP code with the ones for the next `hotp_window`
+    /// counters, so that the token can be pressed a few times without
+    /// logging in. The counter is advanced past the matching one.
+    fn compare_code(&self, code: &str) -> Option<bool> {
+        let Ok(secret) = Secret::Encoded(
+            self.config
+                .hotp_secret
+                .as_ref()
+                .expect("Bug: `config.hotp_secret` should not be `None` here")
+                .expose()
+                .to_string(),
+        )
+        .to_bytes() else {
+            error!("Invalid HOTP secret");
+            return None;
+        };
+        // HOTP is TOTP with the counter in place of the time and a step of 1
+        let hotp = TOTP {
+            algorithm: self.hashtype,
+            digits: self
+                .config
+                .hotp_digits
+                .expect("Bug: `config.hotp_digits` should not be `None` here")
+                as usize,
+            skew: 0,
+            step: 1,
+            secret,
+        };
+        let window = self
+            .config
+            .hotp_window
+            .expect("Bug: `config.hotp_window` should not be `None` here");
+        self.advance_counter(|next_counter| {
+            (next_counter..=next_counter.saturating_add(window))
+                .find(|counter| hotp.check(code, *counter))
+        })
+        .map_err(|e| error!("Cannot update HOTP state: {e}"))
+        .ok()
+    }
+
+    /// Find the matching counter with `find_counter` starting from the next
+    /// expected one, holding the lock so that no counter is used twice
+    fn advance_counter(
+        &self,
+        find_counter: impl FnOnce(u64) -> Option<u64>,
+    ) -> Result<bool, state::Error> {
+        let username = whoami::username().unwrap_or_default();
+        let state = LockedState::open_state(self.config, STATE_FILE)?;
+        let mut hotp_state: HotpState = state.read()?;
+        let next_counter = hotp_state
+            .next_counters
+            .get(&username)
+            .copied()
+            .unwrap_or_default();
+        let Some(counter) = find_counter(next_counter) else {
+            return Ok(false);
+        };
+        hotp_state
+            .next_counters
+            .insert(username, counter.saturating_add(1));
+        state.write(&hotp_state)?;
+        Ok(true)
+    }
+}
+
+impl<'a> Authenticator<'a> for HotpAuthenticator<'a> {
+    fn init(config: &'a SecRcCfg) -> Self {
+        let hashtype = config
+            .hotp_hash
+            .as_deref()
+            .map_or(Algorithm::SHA1, |hash| parse_hash("hotp_hash", hash));
+        let enabled = config.hotp_secret.is_some();
+        HotpAuthenticator {
+            config,
+            enabled,
+            hashtype,
+        }
+    }
+
+    fn is_accepted_login(&self, lockout: &Lockout) -> Option<bool> {
+        if self.enabled {
+            let mut tries: u8 = 0;
+            while tries < 3 {
+                tries += 1;
+                let attempt = lockout.begin_attempt();
+                let input = match terminal::read_hidden("Enter the code displayed on your token: ")
+                {
+                    Ok(input) => input,
+                    Err(error) => {
+                        // Most likely a disconnection, which still counts
+                        error!("{error}");
+                        return None;
+                    }
+                };
+                let input = input.trim();
+                if input.is_empty() {
+                    // Skip this authenticator
+                    attempt.forget();
+                    return None;
+                }
+                match self.compare_code(input) {
+                    Some(false) => warn!("Wrong code {:?}", Redacted(input)),
+                    accepted => {
+                        attempt.forget();
+                        return accepted;
+                    }
+                }
+            }
+            // Maximum number of tries exceeded
+            error!("Maximum number of retries exceeded");
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    fn is_accepted_exec(&self, cmd: &mut String, lockout: &Lockout) -> Option<bool> {
+        if self.enabled {
+            let hotp_digits = self
+                .config
+                .hotp_digits
+                .expect("Bug: `config.hotp_digits` should not be `None` here")
+                as usize;
+            // A missing code becomes None
+            let input = cmd.get(0..hotp_digits)?;
+            if !is_code(input) {
+                return None;
+            }
+            let attempt = lockout.begin_attempt();
+            match self.compare_code(input) {
+                Some(true) => {
+                    attempt.forget();
+                    // Remove the code
+                    *cmd = cmd[hotp_digits..cmd.len()].to_string();
+                    Some(true)
+                }
+                // Still counted, but the next authenticator may accept it
+                Some(false) => None,
+                None => {
+                    attempt.forget();
+                    None
+                }
+            }
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::SecretString;
+    use std::{env, fs, process};
+
+    /// RFC 4226 Appendix D, the secret "12345678901234567890"
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    const CODES: [&str; 10] = [
+        "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871",
+        "520489",
+    ];
+
+    fn state_config(name: &str, window: u64) -> SecRcCfg {
+        let dir = env::temp_dir().join(format!("sibsecsh-test-{}-{name}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut config = SecRcCfg::default();
+        config.tmpdir = Some(dir.display().to_string());
+        config.hotp_secret = Some(SecretString::from(String::from(SECRET)));
+        config.hotp_window = Some(window);
+        config
+    }
+
+    #[test]
+    fn matches_rfc4226_vectors() {
+        let config = state_config("hotp-rfc", 0);
+        let authenticator = HotpAuthenticator::init(&config);
+        for code in CODES {
+            assert_eq!(authenticator.compare_code(code), Some(true), "{code}");
+        }
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+
+    #[test]
+    fn window_ends_after_hotp_window_counters() {
+        let config = state_config("hotp-window", 2);
+        let authenticator = HotpAuthenticator::init(&config);
+        // next + window + 1
+        assert_eq!(authenticator.compare_code(CODES[3]), Some(false));
+        // next + window
+        assert_eq!(authenticator.compare_code(CODES[2]), Some(true));
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+
+    #[test]
+    fn counter_moves_past_the_match() {
+        let config = state_config("hotp-advance", 2);
+        let authenticator = HotpAuthenticator::init(&config);
+        assert_eq!(authenticator.compare_code(CODES[1]), Some(true));
+        // Both the matched counter and the skipped one are used up
+        assert_eq!(authenticator.compare_code(CODES[1]), Some(false));
+        assert_eq!(authenticator.compare_code(CODES[0]), Some(false));
+        // The window now starts right after the match
+        assert_eq!(authenticator.compare_code(CODES[4]), Some(true));
+        assert_eq!(authenticator.compare_code(CODES[5]), Some(true));
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+}
@@ -18,11 +18,46 @@
 //
 
 use crate::auth::Authenticator;
-use crate::config::SecRcCfg;
+use crate::config::{self, SecRcCfg};
+use crate::lockout::Lockout;
+use crate::secret::Redacted;
+use crate::state::{self, LockedState};
+use crate::terminal;
 use log::{error, warn};
-use std::io::{Write, stdin, stdout};
+use qrcode::QrCode;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::types::QrError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
+use thiserror::Error;
 use totp_rs::{Algorithm, Secret, TOTP};
 
+const STATE_FILE: &str = "totp.toml";
+
+/// Persistent state to prevent code reuse
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct TotpState {
+    /// Last accepted time step of each user
+    #[serde(default)]
+    last_steps: BTreeMap<String, u64>,
+}
+
+/// Error type for TOTP enrollment
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("cannot read input")]
+    Input(#[from] std::io::Error),
+    #[error("cannot render QR code: {0}")]
+    QrCode(#[from] QrError),
+    #[error("cannot retrieve system time")]
+    SystemTime(#[from] SystemTimeError),
+    #[error("verification failed")]
+    Verification,
+    #[error("cannot save configuration: {0}")]
+    SaveConfig(#[from] config::Error),
+}
+
 pub struct TotpAuthenticator<'a> {
     config: &'a SecRcCfg,
     enabled: bool,
@@ -30,53 +65,184 @@ pub struct TotpAuthenticator<'a> {
 }
 
 impl TotpAuthenticator<'_> {
-    /// Compares a TOTP code with the correct one, tolerating the one before
-    /// and the one after to take networking and time inaccuracy into account.
-    fn compare_code(&self, code: &str) -> Option<bool> {
-        let Ok(secret) = Secret::Encoded(
-            self.config
-                .totp_secret
-                .clone()
-                .expect("Bug: `config.totp_secret` should not be `None` here"),
-        )
-        .to_bytes() else {
-            error!("Invalid TOTP secret");
-            return None;
-        };
-        let totp = TOTP {
+    /// Create a TOTP generator with the configured parameters
+    fn build_totp(&self, secret: Vec<u8>, skew: u8) -> TOTP {
+        TOTP {
             algorithm: self.hashtype,
             digits: self
                 .config
                 .totp_digits
                 .expect("Bug: `config.totp_digit` should not be `None` here")
                 as usize,
-            skew: 1,
+            skew,
             step: self
                 .config
                 .totp_timestep
                 .expect("Bug: `config.totp_timestep` should not be `None` here"),
             secret,
+        }
+    }
+
+    /// Compares a TOTP code with the correct one, tolerating the one before
+    /// and the one after to take networking and time inaccuracy into account.
+    /// A code is only accepted once.
+    fn compare_code(&self, code: &str) -> Option<bool> {
+        let Ok(secret) = Secret::Encoded(
+            self.config
+                .totp_secret
+                .as_ref()
+                .expect("Bug: `config.totp_secret` should not be `None` here")
+                .expose()
+                .to_string(),
+        )
+        .to_bytes() else {
+            error!("Invalid TOTP secret");
+            return None;
         };
-        totp.check_current(code)
+        // Each time step is checked separately to find the matching one
+        let totp = self.build_totp(secret, 0);
+        let step = totp.step;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
             .map_err(|e| error!("Cannot retrieve system time: {e}"))
+            .ok()?
+            .as_secs();
+        let current_step = now / step;
+        let Some(matched_step) = (current_step.saturating_sub(1)..=current_step + 1)
+            .find(|time_step| totp.check(code, time_step * step))
+        else {
+            return Some(false);
+        };
+        self.consume_step(matched_step)
+            .map_err(|e| error!("Cannot update TOTP state: {e}"))
             .ok()
     }
+
+    /// Remember `time_step` as used, rejecting it unless it is later than
+    /// the last accepted one so that a code cannot be replayed
+    fn consume_step(&self, time_step: u64) -> Result<bool, state::Error> {
+        let username = whoami::username().unwrap_or_default();
+        let state = LockedState::open_state(self.config, STATE_FILE)?;
+        let mut totp_state: TotpState = state.read()?;
+        if let Some(&last_step) = totp_state.last_steps.get(&username)
+            && time_step <= last_step
+        {
+            warn!("Replayed TOTP code");
+            return Ok(false);
+        }
+        totp_state.last_steps.insert(username, time_step);
+        state.write(&totp_state)?;
+        Ok(true)
+    }
 }
 
-impl<'a> Authenticator<'a> for TotpAuthenticator<'a> {
-    fn init(config: &'a SecRcCfg) -> Self {
-        let mut hashtype = Algorithm::SHA1;
-        if let Some(config_hash_type) = &config.totp_hash {
-            let strlen = config_hash_type.len();
-
-            if config_hash_type[0..3].to_uppercase() != "SHA" {
-                error!("Invalid totp_hash type");
-            } else if config_hash_type[strlen - 3..strlen] == *"512" {
-                hashtype = Algorithm::SHA512;
-            } else if config_hash_type[strlen - 3..strlen] == *"256" {
-                hashtype = Algorithm::SHA256;
+/// Whether a `-c` prefix looks like a numeric code rather than a command,
+/// so that a mismatch counts as a failed attempt
+pub fn is_code(input: &str) -> bool {
+    input.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Get the algorithm of a hash name such as "SHA256"
+pub fn hash_from_name(config_hash_type: &str) -> Option<Algorithm> {
+    if !config_hash_type
+        .get(0..3)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("SHA"))
+    {
+        None
+    } else if config_hash_type.ends_with("512") {
+        Some(Algorithm::SHA512)
+    } else if config_hash_type.ends_with("256") {
+        Some(Algorithm::SHA256)
+    } else if config_hash_type.ends_with('1') {
+        Some(Algorithm::SHA1)
+    } else {
+        None
+    }
+}
+
+/// Parse a hash name in the option `option`, falling back to SHA1 if it is
+/// invalid
+pub fn parse_hash(option: &str, config_hash_type: &str) -> Algorithm {
+    hash_from_name(config_hash_type).unwrap_or_else(|| {
+        error!("Invalid {option} type");
+        Algorithm::SHA1
+    })
+}
+
+/// Percent-encode a component of an `otpauth://` URI
+fn percent_encode(component: &str) -> String {
+    component
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                char::from(byte).to_string()
+            } else {
+                format!("%{byte:02X}")
             }
+        })
+        .collect()
+}
+
+impl TotpAuthenticator<'_> {
+    /// Generate a new secret, show it to the user and save it into the user
+    /// configuration once a code has been verified
+    pub fn enroll(&self) -> Result<(), Error> {
+        // The inline secret would conflict with them and the file be refused
+        let path = self.config.user_config_path()?;
+        config::check_user_secret(&path, "totp_secret")?;
+        // Same length as the hash output, as recommended by RFC 6238
+        let secret_len = match self.hashtype {
+            Algorithm::SHA1 => 20,
+            Algorithm::SHA256 => 32,
+            Algorithm::SHA512 => 64,
+        };
+        let secret: Vec<u8> = (0..secret_len).map(|_| rand::random()).collect();
+        let totp = self.build_totp(secret, 1);
+        let encoded_secret = totp.get_secret_base32();
+        let label = percent_encode(&format!(
+            "{}@{}",
+            whoami::username().unwrap_or_default(),
+            whoami::hostname().unwrap_or_default()
+        ));
+        let uri = format!(
+            "otpauth://totp/sibsecsh:{label}?secret={encoded_secret}&issuer=sibsecsh&algorithm={}&digits={}&period={}",
+            self.hashtype, totp.digits, totp.step
+        );
+        // Inverted so that it shows up on a dark terminal
+        let qr_code = QrCode::new(uri.as_bytes())?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build();
+        println!("{qr_code}");
+        println!("{uri}");
+        if self.enabled {
+            println!("The existing TOTP secret will be replaced.");
         }
+        for _ in 0..3 {
+            let input =
+                terminal::read_hidden("Enter the code displayed on your device to confirm: ")?;
+            let input = input.trim();
+            if input.is_empty() {
+                break;
+            }
+            if totp.check_current(input)? {
+                let path = config::set_user_value(path, "totp_secret", &encoded_secret)?;
+                println!("TOTP secret saved to {}", path.display());
+                return Ok(());
+            }
+            warn!("Wrong code");
+        }
+        Err(Error::Verification)
+    }
+}
+
+impl<'a> Authenticator<'a> for TotpAuthenticator<'a> {
+    fn init(config: &'a SecRcCfg) -> Self {
+        let hashtype = config
+            .totp_hash
+            .as_deref()
+            .map_or(Algorithm::SHA1, |hash| parse_hash("totp_hash", hash));
         let enabled = config.totp_secret.is_some();
         TotpAuthenticator {
             config,
@@ -85,28 +251,34 @@ impl<'a> Authenticator<'a> for TotpAuthenticator<'a> {
         }
     }
 
-    fn is_accepted_login(&self) -> Option<bool> {
+    fn is_accepted_login(&self, lockout: &Lockout) -> Option<bool> {
         if self.enabled {
-            let stdin = stdin();
             let mut tries: u8 = 0;
             while tries < 3 {
-                let mut input = String::new();
                 tries += 1;
-                print!("Enter the code displayed on your device: ");
-                stdout().flush().ok();
-                if let Err(error) = stdin.read_line(&mut input) {
-                    error!("{error}");
-                    return None;
-                }
+                let attempt = lockout.begin_attempt();
+                let input = match terminal::read_hidden("Enter the code displayed on your device: ")
+                {
+                    Ok(input) => input,
+                    Err(error) => {
+                        // Most likely a disconnection, which still counts
+                        error!("{error}");
+                        return None;
+                    }
+                };
                 let input = input.trim();
                 if input.is_empty() {
                     // Skip this authenticator
+                    attempt.forget();
                     return None;
                 }
-                if self.compare_code(input)? {
-                    return Some(true);
+                match self.compare_code(input) {
+                    Some(false) => warn!("Wrong code {:?}", Redacted(input)),
+                    accepted => {
+                        attempt.forget();
+                        return accepted;
+                    }
                 }
-                warn!("Wrong code {input:?}");
             }
             // Maximum number of tries exceeded
             error!("Maximum number of retries exceeded");
@@ -116,24 +288,32 @@ impl<'a> Authenticator<'a> for TotpAuthenticator<'a> {
         }
     }
 
-    fn is_accepted_exec(&self, cmd: &mut String) -> Option<bool> {
+    fn is_accepted_exec(&self, cmd: &mut String, lockout: &Lockout) -> Option<bool> {
         if self.enabled {
             let totp_digits = self
                 .config
                 .totp_digits
                 .expect("Bug: `config.totp_digit` should not be `None` here")
                 as usize;
-            if cmd.len() < totp_digits {
+            // A missing code becomes None
+            let input = cmd.get(0..totp_digits)?;
+            if !is_code(input) {
                 return None;
             }
-            // A missing code becomes None
-            let input = &cmd[0..totp_digits];
-            if self.compare_code(input)? {
-                // Remove the code
-                *cmd = cmd[totp_digits..cmd.len()].to_string();
-                Some(true)
-            } else {
-                None
+            let attempt = lockout.begin_attempt();
+            match self.compare_code(input) {
+                Some(true) => {
+                    attempt.forget();
+                    // Remove the code
+                    *cmd = cmd[totp_digits..cmd.len()].to_string();
+                    Some(true)
+                }
+                // Still counted, but the next authenticator may accept it
+                Some(false) => None,
+                None => {
+                    attempt.forget();
+                    None
+                }
             }
         } else {
             None
@@ -229,3 +409,68 @@ fn b64_to_hex(b64: &str) -> Option<String> {
     Some(result)
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::SecretString;
+    use std::{env, fs, process};
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXP";
+
+    fn state_config(name: &str) -> SecRcCfg {
+        let dir = env::temp_dir().join(format!("sibsecsh-test-{}-{name}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut config = SecRcCfg::default();
+        config.tmpdir = Some(dir.display().to_string());
+        config.totp_secret = Some(SecretString::from(String::from(SECRET)));
+        config
+    }
+
+    fn current_code(authenticator: &TotpAuthenticator) -> String {
+        let secret = Secret::Encoded(String::from(SECRET)).to_bytes().unwrap();
+        authenticator
+            .build_totp(secret, 0)
+            .generate_current()
+            .unwrap()
+    }
+
+    #[test]
+    fn consume_step_only_accepts_later_steps() {
+        let config = state_config("totp-steps");
+        let authenticator = TotpAuthenticator::init(&config);
+        assert!(authenticator.consume_step(100).unwrap());
+        assert!(!authenticator.consume_step(100).unwrap());
+        assert!(!authenticator.consume_step(99).unwrap());
+        assert!(authenticator.consume_step(101).unwrap());
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+
+    #[test]
+    fn login_code_is_not_replayed() {
+        let config = state_config("totp-login");
+        let authenticator = TotpAuthenticator::init(&config);
+        let code = current_code(&authenticator);
+        assert_eq!(authenticator.compare_code(&code), Some(true));
+        assert_eq!(authenticator.compare_code(&code), Some(false));
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+
+    #[test]
+    fn exec_code_is_not_replayed() {
+        let config = state_config("totp-exec");
+        let authenticator = TotpAuthenticator::init(&config);
+        let lockout = Lockout::new(&config, "user", "");
+        let code = current_code(&authenticator);
+        let mut cmd = format!("{code}ls -la");
+        assert_eq!(
+            authenticator.is_accepted_exec(&mut cmd, &lockout),
+            Some(true)
+        );
+        assert_eq!(cmd, "ls -la");
+        let mut cmd = format!("{code}ls -la");
+        assert_eq!(authenticator.is_accepted_exec(&mut cmd, &lockout), None);
+        assert_eq!(cmd, format!("{code}ls -la"));
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+}
@@ -18,41 +18,191 @@
 //
 
 use crate::auth::Authenticator;
-use crate::config::SecRcCfg;
-use log::error;
+use crate::config::{SecRcCfg, YubicoKey};
+use crate::lockout::Lockout;
+use crate::secret::SecretString;
+use crate::state::{self, LockedState};
+use crate::terminal;
+use aes::Aes128;
+use aes::cipher::{Block, BlockDecrypt, KeyInit};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
 use rand::RngExt;
 use rand::distr::Alphanumeric;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 use std::collections::BTreeMap;
-use std::io::{Write, stdin, stdout};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use ureq::Agent;
+use zeroize::{Zeroize, Zeroizing};
 
-pub struct YubicoAuthenticator {
+type HmacSha1 = Hmac<Sha1>;
+
+const STATE_FILE: &str = "yubico.toml";
+/// Length of an OTP, including the public ID
+pub const OTP_LEN: usize = 44;
+pub const MODHEX_ALPHABET: &str = "cbdefghijklnrtuv";
+/// CRC of a decrypted OTP including its own checksum
+const CRC_OK_RESIDUE: u16 = 0xf0b8;
+
+/// Counters of an OTP, compared lexicographically
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Counter {
+    /// Incremented every time the key is plugged in
+    session: u16,
+    /// Incremented with every OTP within a session
+    usage: u8,
+}
+
+/// Persistent state of offline validation
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct YubicoState {
+    /// Last accepted counters of each public ID
+    #[serde(default)]
+    counters: BTreeMap<String, Counter>,
+}
+
+/// Parameters for validating OTPs locally
+struct OfflineKey {
+    aes_key: [u8; 16],
+    private_id: [u8; 6],
+}
+
+impl Drop for OfflineKey {
+    fn drop(&mut self) {
+        self.aes_key.zeroize();
+        self.private_id.zeroize();
+    }
+}
+
+/// A security key registered to the user
+struct RegisteredKey {
+    /// The first 12 characters of its OTPs
+    public_id: String,
+    /// Name used in log messages
+    label: String,
+    /// OTPs are validated without network if `Some`
+    offline_key: Option<OfflineKey>,
+}
+
+pub struct YubicoAuthenticator<'a> {
+    config: &'a SecRcCfg,
     // Also serves as `enabled`
-    yubico_id: Option<String>,
+    keys: Vec<RegisteredKey>,
+    client_id: u64,
+    /// Decoded `yubico_api_key`, requests and responses are not signed if `None`
+    api_key: Option<Zeroizing<Vec<u8>>>,
+}
+
+/// Compute the signature of `params` as specified by the validation protocol:
+/// HMAC-SHA1 of all pairs except `h`, sorted by key and joined with `&`
+fn sign(api_key: &[u8], params: &BTreeMap<String, String>) -> HmacSha1 {
+    let message = params
+        .iter()
+        .filter(|(key, _)| *key != "h")
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+    let mut mac =
+        <HmacSha1 as Mac>::new_from_slice(api_key).expect("HMAC can take a key of any size");
+    mac.update(message.as_bytes());
+    mac
 }
 
-const YUBICO_SERVER: &str = "https://api.yubico.com/wsapi/2.0/verify";
+/// Check the `h` signature of a response
+fn verify_signature(api_key: &[u8], params: &BTreeMap<String, String>) -> bool {
+    let Some(signature) = params.get("h").and_then(|h| BASE64_STANDARD.decode(h).ok()) else {
+        return false;
+    };
+    sign(api_key, params).verify_slice(&signature).is_ok()
+}
 
-fn verify_otp(otp: &str) -> Result<bool, String> {
-    let mut rng = rand::rng();
-    // Numeric id
-    let id: i32 = rng.random_range(0..1_000);
-    // Random nonce
-    let nonce_len = rng.random_range(16..41);
-    let nonce: String = rng
-        .sample_iter(&Alphanumeric)
-        .take(nonce_len)
-        .map(char::from)
+/// Decode a hexadecimal string, such as a key
+fn decode_hex(hex: &str) -> Option<Zeroizing<Vec<u8>>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .map(Zeroizing::new)
+}
+
+/// Decode a modhex string, the encoding used by Yubico OTPs
+fn decode_modhex(modhex: &str) -> Option<Vec<u8>> {
+    let nibbles: Option<Vec<u8>> = modhex
+        .chars()
+        .map(|ch| {
+            MODHEX_ALPHABET
+                .find(ch)
+                .and_then(|value| u8::try_from(value).ok())
+        })
         .collect();
+    let nibbles = nibbles?;
+    if !nibbles.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(
+        nibbles
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
+
+/// CRC-16 (ISO 13239) as used in Yubico OTPs
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            let lsb = crc & 1;
+            crc >>= 1;
+            if lsb != 0 {
+                crc ^= 0x8408;
+            }
+        }
+    }
+    crc
+}
+
+/// Parse the offline validation parameters of the key called `label`
+fn parse_offline_key(label: &str, aes_key: &str, private_id: Option<&str>) -> Option<OfflineKey> {
+    let aes_key: Option<Zeroizing<[u8; 16]>> =
+        decode_hex(aes_key).and_then(|key| key.as_slice().try_into().ok().map(Zeroizing::new));
+    let private_id: Option<Zeroizing<[u8; 6]>> = private_id
+        .and_then(decode_hex)
+        .and_then(|private_id| private_id.as_slice().try_into().ok().map(Zeroizing::new));
+    match (aes_key, private_id) {
+        (Some(aes_key), Some(private_id)) => Some(OfflineKey {
+            aes_key: *aes_key,
+            private_id: *private_id,
+        }),
+        (None, _) => {
+            error!("Invalid AES key for YubiKey {label}");
+            None
+        }
+        (_, None) => {
+            error!("Invalid or missing private ID for YubiKey {label}");
+            None
+        }
+    }
+}
+
+/// Send a validation request to `server` and parse the response
+fn query_server(
+    agent: &Agent,
+    server: &str,
+    params: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>, String> {
     let mut last_error = String::new();
     for _ in 0..3 {
-        // TODO: signing
-        let resp = ureq::get(YUBICO_SERVER)
-            .query("id", id.to_string())
-            .query("nonce", &nonce)
-            .query("otp", otp)
-            .call();
+        let resp = agent.get(server).query_pairs(params).call();
         if let Ok(resp) = resp {
-            // Successful response. Let's verify
             let result = resp
                 .into_body()
                 .read_to_string()
@@ -67,102 +217,464 @@ fn verify_otp(otp: &str) -> Result<bool, String> {
                     .ok_or_else(|| format!("Malformed reply {line:?}: missing key or value"))?;
                 kvs.insert(key.to_string(), value.to_string());
             }
-            if let Some(returned_otp) = kvs.get("otp") {
-                if returned_otp != otp {
-                    error!("OTP in the response does not match the request");
-                    // Reject
-                    return Ok(false);
-                }
-            } else {
-                return Err(String::from("`otp` not in the response"));
+            return Ok(kvs);
+        }
+        last_error = resp.unwrap_err().to_string();
+    }
+    Err(format!("{server}: {last_error}"))
+}
+
+impl YubicoAuthenticator<'_> {
+    /// Check a response from `server`.
+    /// `None` means that another server may still accept the OTP.
+    fn check_response(
+        &self,
+        server: &str,
+        kvs: &BTreeMap<String, String>,
+        otp: &str,
+        nonce: &str,
+    ) -> Result<Option<bool>, String> {
+        if let Some(api_key) = &self.api_key
+            && !verify_signature(api_key, kvs)
+        {
+            error!("Signature of the response from {server} is invalid");
+            // Reject
+            return Ok(Some(false));
+        }
+        if let Some(returned_otp) = kvs.get("otp") {
+            if returned_otp != otp {
+                error!("OTP in the response does not match the request");
+                // Reject
+                return Ok(Some(false));
             }
-            if let Some(returned_nonce) = kvs.get("nonce") {
-                if *returned_nonce != nonce {
-                    error!("Nonce in the response does not match the request");
-                    // Reject
-                    return Ok(false);
-                }
-            } else {
-                return Err(String::from("`nonce` not in the response"));
+        } else {
+            return Err(String::from("`otp` not in the response"));
+        }
+        if let Some(returned_nonce) = kvs.get("nonce") {
+            if *returned_nonce != nonce {
+                error!("Nonce in the response does not match the request");
+                // Reject
+                return Ok(Some(false));
+            }
+        } else {
+            return Err(String::from("`nonce` not in the response"));
+        }
+        match kvs.get("status").map(String::as_str) {
+            None => Err(String::from("`status` not in the response")),
+            Some("OK") => Ok(Some(true)),
+            Some("REPLAYED_REQUEST") => {
+                // Expected when the servers synchronize with each other
+                info!("{server} has already seen this request");
+                Ok(None)
             }
-            // TODO: verify signature
-            return match kvs.get("status").map(String::as_str) {
-                None => Err(String::from("`status` not in the response")),
-                Some("OK") => Ok(true),
-                Some(status) => {
-                    error!("Status {status} is not OK");
-                    // Reject
-                    Ok(false)
+            Some("REPLAYED_OTP") => {
+                error!("OTP has already been used, possibly a replay attack");
+                Ok(Some(false))
+            }
+            Some("BAD_SIGNATURE") => {
+                error!(
+                    "{server} rejected the signature, check yubico_client_id and yubico_api_key"
+                );
+                Ok(Some(false))
+            }
+            Some(status) => {
+                error!("Status {status} is not OK");
+                // Reject
+                Ok(Some(false))
+            }
+        }
+    }
+
+    /// Validate an OTP with the local key if configured, or with the
+    /// validation servers otherwise
+    fn verify_otp(&self, key: &RegisteredKey, otp: &str) -> Result<bool, String> {
+        match &key.offline_key {
+            Some(offline_key) => self.verify_otp_offline(offline_key, otp),
+            None => self.verify_otp_online(otp),
+        }
+    }
+
+    /// Decrypt and check the OTP locally, as a validation server would
+    fn verify_otp_offline(&self, offline_key: &OfflineKey, otp: &str) -> Result<bool, String> {
+        let Some((public_id, token)) = otp.split_at_checked(12) else {
+            error!("Malformed OTP");
+            return Ok(false);
+        };
+        let Some(ciphertext) = decode_modhex(token).filter(|bytes| bytes.len() == 16) else {
+            error!("Malformed OTP");
+            return Ok(false);
+        };
+        let mut block = Block::<Aes128>::clone_from_slice(&ciphertext);
+        Aes128::new(&offline_key.aes_key.into()).decrypt_block(&mut block);
+        if crc16(&block) != CRC_OK_RESIDUE {
+            error!("Invalid OTP checksum, the AES key may be wrong");
+            return Ok(false);
+        }
+        if block[0..6] != offline_key.private_id {
+            error!("Private ID in the OTP does not match");
+            return Ok(false);
+        }
+        let counter = Counter {
+            // The highest bit is a flag
+            session: u16::from_le_bytes([block[6], block[7]]) & 0x7fff,
+            usage: block[11],
+        };
+        self.consume_counter(public_id, counter)
+            .map_err(|e| format!("Cannot update Yubico state: {e}"))
+    }
+
+    /// Remember `counter` as used, rejecting it unless it is later than the
+    /// last accepted one so that an OTP cannot be replayed
+    fn consume_counter(&self, public_id: &str, counter: Counter) -> Result<bool, state::Error> {
+        let state = LockedState::open_state(self.config, STATE_FILE)?;
+        let mut yubico_state: YubicoState = state.read()?;
+        if let Some(last_counter) = yubico_state.counters.get(public_id)
+            && counter <= *last_counter
+        {
+            error!("OTP has already been used, possibly a replay attack");
+            return Ok(false);
+        }
+        yubico_state.counters.insert(public_id.to_string(), counter);
+        state.write(&yubico_state)?;
+        Ok(true)
+    }
+
+    /// Query all configured validation servers in parallel, as recommended
+    /// by the validation protocol, and use the first conclusive response
+    fn verify_otp_online(&self, otp: &str) -> Result<bool, String> {
+        let mut rng = rand::rng();
+        // Random nonce
+        let nonce_len = rng.random_range(16..41);
+        let nonce: String = rng
+            .sample_iter(&Alphanumeric)
+            .take(nonce_len)
+            .map(char::from)
+            .collect();
+        let mut params: BTreeMap<String, String> = BTreeMap::new();
+        params.insert(String::from("id"), self.client_id.to_string());
+        params.insert(String::from("nonce"), nonce.clone());
+        params.insert(String::from("otp"), otp.to_string());
+        if let Some(sl) = self.config.yubico_sl {
+            params.insert(String::from("sl"), sl.to_string());
+        }
+        let timeout = self
+            .config
+            .yubico_timeout
+            .expect("Bug: `config.yubico_timeout` should not be `None` here");
+        params.insert(String::from("timeout"), timeout.to_string());
+        if let Some(api_key) = &self.api_key {
+            let signature = BASE64_STANDARD.encode(sign(api_key, &params).finalize().into_bytes());
+            params.insert(String::from("h"), signature);
+        }
+        // Leave the server some time to synchronize before giving up
+        let agent: Agent = Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(timeout + 5)))
+            .build()
+            .into();
+        let servers = self
+            .config
+            .yubico_servers
+            .as_ref()
+            .expect("Bug: `config.yubico_servers` should not be `None` here");
+        let (sender, receiver) = mpsc::channel();
+        for server in servers {
+            let sender = sender.clone();
+            let agent = agent.clone();
+            let server = server.clone();
+            let params = params.clone();
+            thread::spawn(move || {
+                let result = query_server(&agent, &server, &params);
+                // The receiver is gone if another server already answered
+                sender.send((server, result)).ok();
+            });
+        }
+        // Only the spawned threads should keep the channel open
+        drop(sender);
+        let mut last_error = String::from("No validation server responded");
+        for (server, result) in receiver {
+            match result.and_then(|kvs| self.check_response(&server, &kvs, otp, &nonce)) {
+                Ok(Some(is_accepted)) => return Ok(is_accepted),
+                Ok(None) => (),
+                Err(e) => {
+                    warn!("{e}");
+                    last_error = e;
                 }
-            };
+            }
         }
-        last_error = resp.unwrap_err().to_string();
+        Err(last_error)
     }
-    Err(last_error)
 }
 
-impl Authenticator<'_> for YubicoAuthenticator {
-    fn init(config: &SecRcCfg) -> Self {
-        Self {
-            yubico_id: config.yubico_id.as_ref().and_then(|supplied_yubico_id| {
-                if supplied_yubico_id.len() < 12 {
-                    None
+impl YubicoAuthenticator<'_> {
+    /// Find the registered key that generated `otp`
+    fn find_key(&self, otp: &str) -> Option<&RegisteredKey> {
+        let public_id = otp.get(0..12)?;
+        self.keys.iter().find(|key| key.public_id == public_id)
+    }
+}
+
+impl<'a> Authenticator<'a> for YubicoAuthenticator<'a> {
+    fn init(config: &'a SecRcCfg) -> Self {
+        let api_key = config.yubico_api_key.as_ref().and_then(|api_key| {
+            BASE64_STANDARD
+                .decode(api_key.expose())
+                .map(Zeroizing::new)
+                .map_err(|_| error!("Invalid yubico_api_key"))
+                .ok()
+        });
+        // `yubico_id` goes with the top-level offline validation parameters
+        let single_key = config.yubico_id.as_ref().map(|id| YubicoKey {
+            id: id.clone(),
+            label: None,
+            aes_key: config.yubico_aes_key.clone(),
+            private_id: config.yubico_private_id.clone(),
+            ..YubicoKey::default()
+        });
+        let keys: Vec<RegisteredKey> = single_key
+            .iter()
+            .chain(config.yubico_keys.iter().flatten())
+            .filter_map(|key| {
+                let label = key.label.clone().unwrap_or_else(|| key.id.clone());
+                let Some(public_id) = key.id.get(0..12) else {
+                    error!("ID of YubiKey {label} is too short");
+                    return None;
+                };
+                let offline_key = if let Some(aes_key) = &key.aes_key {
+                    // Do not fall back to the validation servers
+                    Some(parse_offline_key(
+                        &label,
+                        aes_key.expose(),
+                        key.private_id.as_ref().map(SecretString::expose),
+                    )?)
+                } else if config.yubico_client_id.is_none() {
+                    error!("YubiKey {label} needs either yubico_client_id or an AES key");
+                    return None;
+                } else if config.yubico_api_key.is_some() && api_key.is_none() {
+                    // Do not silently fall back to unsigned requests
+                    return None;
                 } else {
-                    Some(supplied_yubico_id[0..12].to_string())
-                }
-            }),
+                    None
+                };
+                Some(RegisteredKey {
+                    public_id: public_id.to_string(),
+                    label,
+                    offline_key,
+                })
+            })
+            .collect();
+        if api_key.is_none() && keys.iter().any(|key| key.offline_key.is_none()) {
+            warn!("Yubico validation responses are not verified without yubico_api_key");
+        }
+        Self {
+            config,
+            keys,
+            client_id: config.yubico_client_id.unwrap_or_default(),
+            api_key,
         }
     }
 
-    fn is_accepted_login(&self) -> Option<bool> {
-        if let Some(yubico_id) = &self.yubico_id {
-            print!("Enter your YubiOTP: ");
-            let mut input = String::new();
-            stdout().flush().ok();
-            if let Err(error) = stdin().read_line(&mut input) {
+    fn is_accepted_login(&self, lockout: &Lockout) -> Option<bool> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let attempt = lockout.begin_attempt();
+        let mut input = match terminal::read_hidden("Enter your YubiOTP: ") {
+            Ok(input) => input,
+            Err(error) => {
+                // Most likely a disconnection, which still counts
                 error!("{error}");
                 return None;
             }
-            input = input.trim().to_string();
-            if input.is_empty() {
-                // Skip this authenticator
-                None
-            } else if input.len() < 14 {
-                error!("Malformed OTP");
-                Some(false)
-            } else if input[0..12] != *yubico_id {
-                error!("Incorrect Yubikey ID");
-                Some(false)
-            } else {
-                verify_otp(&input).map_or_else(
-                    |err| {
-                        error!("{err:?}");
-                        None
-                    },
-                    Some,
-                )
+        };
+        input = input.trim().to_string();
+        if input.is_empty() {
+            // Skip this authenticator
+            attempt.forget();
+            None
+        } else if input.len() < 14 {
+            error!("Malformed OTP");
+            Some(false)
+        } else if let Some(key) = self.find_key(&input) {
+            info!("Validating OTP of YubiKey {}", key.label);
+            match self.verify_otp(key, &input) {
+                Ok(false) => Some(false),
+                Ok(true) => {
+                    attempt.forget();
+                    Some(true)
+                }
+                Err(err) => {
+                    attempt.forget();
+                    error!("{err:?}");
+                    None
+                }
             }
         } else {
-            None
+            error!("Incorrect Yubikey ID");
+            Some(false)
         }
     }
 
-    fn is_accepted_exec(&self, cmd: &mut String) -> Option<bool> {
-        if cmd.len() < 44 {
-            return None;
-        }
-        verify_otp(&cmd[0..44]).map_or_else(
-            |err| {
+    fn is_accepted_exec(&self, cmd: &mut String, lockout: &Lockout) -> Option<bool> {
+        let otp = cmd.get(0..OTP_LEN)?;
+        // Not necessarily an OTP, so not a rejection
+        let key = self.find_key(otp)?;
+        info!("Validating OTP of YubiKey {}", key.label);
+        let attempt = lockout.begin_attempt();
+        match self.verify_otp(key, otp) {
+            Ok(false) => Some(false),
+            Ok(true) => {
+                attempt.forget();
+                // Remove the code
+                *cmd = cmd[OTP_LEN..cmd.len()].to_string();
+                Some(true)
+            }
+            Err(err) => {
+                attempt.forget();
                 error!("{err:?}");
                 None
-            },
-            |result| {
-                if result {
-                    // Remove the code
-                    *cmd = cmd[44..cmd.len()].to_string();
-                }
-                Some(result)
-            },
-        )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    /// Example from the Yubico validation protocol documentation
+    const API_KEY: &str = "mG5be6ZJU1qBGz24yPh/ESM3UdU=";
+    const SIGNATURE: &str = "+ja8S3IjbX593/LAgTBixwPNGX4=";
+
+    fn params() -> BTreeMap<String, String> {
+        [
+            ("id", "1"),
+            ("otp", "vvungrrdhvtklknvrtvuvbbkeidikkvgglrvdgrfcdft"),
+            ("nonce", "jrFwbaYFhn0HoxZIsd9LQ6w2ceU"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+    }
+
+    fn api_key() -> Vec<u8> {
+        BASE64_STANDARD.decode(API_KEY).unwrap()
+    }
+
+    #[test]
+    fn sign_matches_documentation() {
+        let signature = sign(&api_key(), &params()).finalize().into_bytes();
+        assert_eq!(BASE64_STANDARD.encode(signature), SIGNATURE);
+    }
+
+    #[test]
+    fn sign_ignores_signature() {
+        let mut params = params();
+        params.insert(String::from("h"), String::from("anything"));
+        let signature = sign(&api_key(), &params).finalize().into_bytes();
+        assert_eq!(BASE64_STANDARD.encode(signature), SIGNATURE);
+    }
+
+    #[test]
+    fn verify_signature_accepts_valid() {
+        let mut params = params();
+        params.insert(String::from("h"), String::from(SIGNATURE));
+        assert!(verify_signature(&api_key(), &params));
+    }
+
+    #[test]
+    fn verify_signature_rejects_tampered() {
+        let mut params = params();
+        params.insert(String::from("h"), String::from(SIGNATURE));
+        params.insert(String::from("id"), String::from("2"));
+        assert!(!verify_signature(&api_key(), &params));
+        params.insert(String::from("id"), String::from("1"));
+        assert!(!verify_signature(b"another key", &params));
+    }
+
+    #[test]
+    fn verify_signature_rejects_missing_or_malformed() {
+        let mut params = params();
+        assert!(!verify_signature(&api_key(), &params));
+        params.insert(String::from("h"), String::from("not base64!"));
+        assert!(!verify_signature(&api_key(), &params));
+    }
+
+    /// Test vector of libyubikey's `ykparse`, with a public ID padded to 12
+    const AES_KEY: &str = "ecde18dbe76fbd0c33330f1c354871db";
+    const PRIVATE_ID: &str = "8792ebfe26cc";
+    const OTP: &str = "ccccdteffujehknhfjbrjnlnldnhcujvddbikngjrtgh";
+
+    #[test]
+    fn crc16_check_value() {
+        // CRC-16/X-25 of "123456789" is 0x906e after the final inversion
+        assert_eq!(crc16(b"123456789"), !0x906e);
+        assert_eq!(crc16(&[]), 0xffff);
+    }
+
+    #[test]
+    fn decode_modhex_alphabet() {
+        assert_eq!(
+            decode_modhex("cbdefghijklnrtuv"),
+            Some(vec![0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef])
+        );
+        assert_eq!(decode_modhex(""), Some(vec![]));
+        assert_eq!(decode_modhex("cbd"), None);
+        assert_eq!(decode_modhex("ca"), None);
+    }
+
+    fn offline_authenticator<'a>(
+        config: &'a SecRcCfg,
+        private_id: &str,
+    ) -> YubicoAuthenticator<'a> {
+        YubicoAuthenticator {
+            config,
+            keys: vec![RegisteredKey {
+                public_id: OTP[0..12].to_string(),
+                label: String::from("test"),
+                offline_key: parse_offline_key("test", AES_KEY, Some(private_id)),
+            }],
+            client_id: 0,
+            api_key: None,
+        }
+    }
+
+    fn state_config(name: &str) -> SecRcCfg {
+        let dir = env::temp_dir().join(format!("sibsecsh-test-{}-{name}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut config = SecRcCfg::default();
+        config.tmpdir = Some(dir.display().to_string());
+        config
+    }
+
+    #[test]
+    fn offline_accepts_once() {
+        let config = state_config("offline-once");
+        let authenticator = offline_authenticator(&config, PRIVATE_ID);
+        let key = authenticator.find_key(OTP).unwrap();
+        assert_eq!(authenticator.verify_otp(key, OTP), Ok(true));
+        // Replayed
+        assert_eq!(authenticator.verify_otp(key, OTP), Ok(false));
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+
+    #[test]
+    fn offline_rejects_wrong_private_id() {
+        let config = state_config("offline-private-id");
+        let authenticator = offline_authenticator(&config, "8792ebfe26cd");
+        let key = authenticator.find_key(OTP).unwrap();
+        assert_eq!(authenticator.verify_otp(key, OTP), Ok(false));
+        fs::remove_dir_all(config.state_path()).ok();
+    }
+
+    #[test]
+    fn offline_rejects_corrupted() {
+        let config = state_config("offline-corrupted");
+        let authenticator = offline_authenticator(&config, PRIVATE_ID);
+        let key = authenticator.find_key(OTP).unwrap();
+        let corrupted = OTP.replace("jrtgh", "jrtgc");
+        assert_eq!(authenticator.verify_otp(key, &corrupted), Ok(false));
+        assert_eq!(authenticator.verify_otp(key, &OTP[0..40]), Ok(false));
+        fs::remove_dir_all(config.state_path()).ok();
     }
 }
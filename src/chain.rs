@@ -0,0 +1,443 @@
+//
+//  Copyright (C) 2021 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::audit::{self, Event};
+use crate::auth::{Authenticator, BypassAuthenticator, LocalIPAuthenticator};
+use crate::auth_backup::{self, BackupCodeAuthenticator};
+use crate::auth_email::{self, EmailAuthenticator};
+use crate::auth_hotp::HotpAuthenticator;
+use crate::auth_totp::TotpAuthenticator;
+use crate::auth_yubico::{self, YubicoAuthenticator};
+use crate::config::{self, ChainEntry, Control, FactorWeight, SecRcCfg};
+use crate::ip::get_from;
+use crate::lockout::Lockout;
+use crate::parse_args::{self, CodeFormat};
+use crate::state;
+use log::{Level, error};
+use std::collections::BTreeSet;
+use thiserror::Error;
+
+/// Error type for running the authenticator chain
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Too many failed attempts, try again in {0} seconds")]
+    LockedOut(u64),
+    #[error("Cannot read lockout state: {0}")]
+    LockoutState(state::Error),
+    #[error("Cannot record failed attempts")]
+    LockoutUnrecorded,
+    #[error("Unknown authenticator {0:?}")]
+    UnknownAuthenticator(String),
+    #[error("Rejected")]
+    Rejected,
+    #[error("Required authenticator {0:?} skipped")]
+    RequiredSkipped(String),
+    #[error("All authenticators skipped")]
+    AllSkipped,
+    #[error("Only {accepted} of {required} factors accepted")]
+    NotEnoughFactors { accepted: usize, required: u32 },
+    #[error("Cannot execute the shell: {0}")]
+    Exec(#[from] config::Error),
+}
+
+/// Function constructing an authenticator from the configuration
+pub type Constructor = for<'a> fn(&'a SecRcCfg) -> Box<dyn Authenticator<'a> + 'a>;
+
+/// All known authenticators by name, in the default chain order
+pub const REGISTRY: &[(&str, Constructor)] = &[
+    ("bypass", |config| {
+        Box::new(BypassAuthenticator::init(config))
+    }),
+    ("local_ip", |config| {
+        Box::new(LocalIPAuthenticator::init(config))
+    }),
+    ("email", |config| Box::new(EmailAuthenticator::init(config))),
+    ("totp", |config| Box::new(TotpAuthenticator::init(config))),
+    ("hotp", |config| Box::new(HotpAuthenticator::init(config))),
+    ("yubico", |config| {
+        Box::new(YubicoAuthenticator::init(config))
+    }),
+    ("backup_codes", |config| {
+        Box::new(BackupCodeAuthenticator::init(config))
+    }),
+];
+
+/// Find the constructor of the authenticator called `name`
+fn lookup(name: &str) -> Option<Constructor> {
+    REGISTRY
+        .iter()
+        .find(|(known_name, _)| *known_name == name)
+        .map(|(_, constructor)| *constructor)
+}
+
+/// Codes that the enabled authenticators among `entries` may take from the
+/// start of the argument to -c, in the order they are taken
+fn code_formats(configuration: &SecRcCfg, entries: &[ChainEntry]) -> Vec<CodeFormat> {
+    let digits = |enabled: bool, len: usize| {
+        enabled.then_some(CodeFormat {
+            len,
+            is_code_byte: u8::is_ascii_digit,
+        })
+    };
+    let configured = |len: Option<u32>| len.map_or(0, |len| len as usize);
+    entries
+        .iter()
+        .filter_map(|entry| match entry.name.as_str() {
+            "totp" => digits(
+                configuration.totp_secret.is_some(),
+                configured(configuration.totp_digits),
+            ),
+            "hotp" => digits(
+                configuration.hotp_secret.is_some(),
+                configured(configuration.hotp_digits),
+            ),
+            "email" => digits(configuration.email.is_some(), auth_email::CODE_LEN),
+            "yubico" => (configuration.yubico_id.is_some()
+                || configuration.yubico_keys.iter().flatten().next().is_some())
+            .then_some(CodeFormat {
+                len: auth_yubico::OTP_LEN,
+                is_code_byte: |byte| auth_yubico::MODHEX_ALPHABET.as_bytes().contains(byte),
+            }),
+            "backup_codes" => configuration
+                .backup_codes_file
+                .is_some()
+                .then_some(CodeFormat {
+                    len: auth_backup::CODE_LEN,
+                    is_code_byte: |byte| auth_backup::CODE_CHARSET.contains(byte),
+                }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether any of `entries` is required
+fn has_required(entries: &[ChainEntry]) -> bool {
+    entries
+        .iter()
+        .any(|entry| entry.control == Control::Required)
+}
+
+/// The N-of-M decision over the outcomes of the chain entries, in order
+struct Tally<'a> {
+    entries: &'a [ChainEntry],
+    required_factors: u32,
+    /// Names of the authenticators that accepted and count as a factor
+    factors: BTreeSet<&'a str>,
+    any_rejected: bool,
+}
+
+impl<'a> Tally<'a> {
+    fn new(entries: &'a [ChainEntry], required_factors: u32) -> Self {
+        Self {
+            entries,
+            required_factors,
+            factors: BTreeSet::new(),
+            any_rejected: false,
+        }
+    }
+
+    /// Record the outcome of the `i`th entry, returning whether access is
+    /// granted or the reason it is denied
+    fn record(&mut self, i: usize, is_accepted: Option<bool>) -> Result<bool, Error> {
+        let entry = &self.entries[i];
+        match (entry.control, is_accepted) {
+            (_, Some(true)) => match entry.counts_as {
+                FactorWeight::All => return Ok(true),
+                FactorWeight::One => {
+                    self.factors.insert(&entry.name);
+                }
+                FactorWeight::Zero => (),
+            },
+            (Control::Requisite | Control::Required, Some(false)) => {
+                return Err(Error::Rejected);
+            }
+            (Control::Required, None) => {
+                return Err(Error::RequiredSkipped(entry.name.clone()));
+            }
+            // The wrong code has been counted by the authenticator
+            (Control::Sufficient | Control::Optional, Some(false)) => self.any_rejected = true,
+            (Control::Sufficient | Control::Requisite | Control::Optional, None) => (),
+        }
+        Ok(self.factors.len() >= self.required_factors as usize
+            && !has_required(&self.entries[i + 1..]))
+    }
+
+    /// Why access is denied once every entry has answered
+    fn denied(self) -> Error {
+        if self.factors.is_empty() && self.any_rejected {
+            Error::Rejected
+        } else if self.factors.is_empty() {
+            Error::AllSkipped
+        } else {
+            Error::NotEnoughFactors {
+                accepted: self.factors.len(),
+                required: self.required_factors,
+            }
+        }
+    }
+}
+
+/// Execute the shell with the (possibly modified) command line
+fn grant(
+    configuration: &SecRcCfg,
+    cmd: Option<String>,
+    mut other_options: Vec<String>,
+) -> Result<(), Error> {
+    let mut exec_options: Vec<String> = Vec::new();
+    if let Some(cmd) = cmd {
+        exec_options.push(String::from("-c"));
+        exec_options.push(cmd);
+    }
+    exec_options.append(&mut other_options);
+    audit::event(
+        Level::Info,
+        Event::ShellExec,
+        None,
+        format_args!("Access granted, executing the shell"),
+    );
+    Ok(configuration.execute_shell(exec_options)?)
+}
+
+/// Run the configured authenticators in order and execute the shell once
+/// enough factors from distinct authenticators are accepted
+pub fn run(configuration: &SecRcCfg) -> Result<(), Error> {
+    let chain = configuration
+        .chain
+        .as_ref()
+        .expect("Bug: `config.chain` should never be `None`");
+    let entries = chain
+        .authenticators
+        .as_ref()
+        .expect("Bug: `config.chain.authenticators` should never be `None`");
+    let required_factors = chain
+        .required_factors
+        .expect("Bug: `config.chain.required_factors` should never be `None`");
+    // First see whether -c is supplied
+    let (mut cmd, other_options) = parse_args::parse_args(&code_formats(configuration, entries));
+    let username = whoami::username().unwrap_or_default();
+    let from_ip = get_from();
+    let lockout = Lockout::new(configuration, &username, &from_ip);
+    let check_lockout = || match lockout.remaining() {
+        Ok(Some(remaining)) => {
+            audit::event(
+                Level::Warn,
+                Event::LockedOut,
+                None,
+                format_args!("Locked out for {remaining} more seconds"),
+            );
+            Err(Error::LockedOut(remaining))
+        }
+        Ok(None) => Ok(()),
+        // Failing open would let attempts go uncounted
+        Err(e) => Err(Error::LockoutState(e)),
+    };
+    let grant_access = |cmd, other_options| {
+        if !lockout.recorded_all() {
+            return Err(Error::LockoutUnrecorded);
+        }
+        if let Err(e) = lockout.reset() {
+            error!("Cannot reset failed attempts: {e}");
+        }
+        grant(configuration, cmd, other_options)
+    };
+    let mut tally = Tally::new(entries, required_factors);
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(constructor) = lookup(&entry.name) else {
+            // Silently skipping it could weaken the chain
+            return Err(Error::UnknownAuthenticator(entry.name.clone()));
+        };
+        // Wrong codes for earlier authenticators may have locked us out
+        check_lockout()?;
+        let authenticator = constructor(configuration);
+        audit::event(
+            Level::Info,
+            Event::FactorPrompted,
+            Some(&entry.name),
+            format_args!("Trying authenticator {:?}", entry.name),
+        );
+        let is_accepted = match cmd.as_mut() {
+            Some(cmd) => authenticator.is_accepted_exec(cmd, &lockout),
+            None => authenticator.is_accepted_login(&lockout),
+        };
+        let (level, event, outcome) = match is_accepted {
+            Some(true) => (Level::Info, Event::FactorAccepted, "accepted"),
+            Some(false) => (Level::Warn, Event::FactorRejected, "rejected"),
+            None => (Level::Info, Event::FactorSkipped, "skipped"),
+        };
+        audit::event(
+            level,
+            event,
+            Some(&entry.name),
+            format_args!("Authenticator {:?} {outcome}", entry.name),
+        );
+        if tally.record(i, is_accepted)? {
+            return grant_access(cmd, other_options);
+        }
+    }
+    Err(tally.denied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChainCfg;
+    use crate::secret::SecretString;
+
+    fn entries(toml: &str) -> Vec<ChainEntry> {
+        toml::from_str::<ChainCfg>(toml)
+            .unwrap()
+            .authenticators
+            .unwrap()
+    }
+
+    /// Feed the outcomes in order, returning the index of the entry that
+    /// granted access
+    fn decide(
+        entries: &[ChainEntry],
+        required_factors: u32,
+        outcomes: &[Option<bool>],
+    ) -> Result<Option<usize>, Error> {
+        let mut tally = Tally::new(entries, required_factors);
+        for (i, is_accepted) in outcomes.iter().enumerate() {
+            if tally.record(i, *is_accepted)? {
+                return Ok(Some(i));
+            }
+        }
+        Err(tally.denied())
+    }
+
+    #[test]
+    fn two_of_three_waits_for_required() {
+        let chain = entries(
+            r#"authenticators = [
+                { name = "totp" },
+                { name = "email" },
+                { name = "yubico", control = "required", counts_as = "one" },
+            ]"#,
+        );
+        let accepted = [Some(true), Some(true), Some(true)];
+        assert!(matches!(decide(&chain, 2, &accepted), Ok(Some(2))));
+        let skipped = [Some(true), Some(true), None];
+        assert!(matches!(
+            decide(&chain, 2, &skipped),
+            Err(Error::RequiredSkipped(name)) if name == "yubico"
+        ));
+        let one_and_required = [Some(true), None, Some(true)];
+        assert!(matches!(decide(&chain, 2, &one_and_required), Ok(Some(2))));
+        let only_one = [None, None, Some(true)];
+        assert!(matches!(
+            decide(&chain, 2, &only_one),
+            Err(Error::NotEnoughFactors {
+                accepted: 1,
+                required: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn zero_does_not_count() {
+        let chain = entries(
+            r#"authenticators = [
+                { name = "local_ip", counts_as = "zero" },
+                { name = "totp" },
+            ]"#,
+        );
+        assert!(matches!(
+            decide(&chain, 1, &[Some(true), None]),
+            Err(Error::AllSkipped)
+        ));
+        assert!(matches!(
+            decide(&chain, 1, &[Some(true), Some(true)]),
+            Ok(Some(1))
+        ));
+    }
+
+    #[test]
+    fn all_grants_right_away() {
+        let chain = entries(
+            r#"authenticators = [
+                { name = "bypass", counts_as = "all" },
+                { name = "yubico", control = "required" },
+            ]"#,
+        );
+        assert!(matches!(decide(&chain, 2, &[Some(true)]), Ok(Some(0))));
+    }
+
+    #[test]
+    fn same_authenticator_counts_once() {
+        let chain = entries(r#"authenticators = [{ name = "totp" }, { name = "totp" }]"#);
+        assert!(matches!(
+            decide(&chain, 2, &[Some(true), Some(true)]),
+            Err(Error::NotEnoughFactors {
+                accepted: 1,
+                required: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn rejections_by_control() {
+        let chain = entries(
+            r#"authenticators = [
+                { name = "totp", control = "optional" },
+                { name = "email", control = "sufficient" },
+                { name = "yubico" },
+            ]"#,
+        );
+        assert!(matches!(
+            decide(&chain, 1, &[Some(false), Some(false), Some(true)]),
+            Ok(Some(2))
+        ));
+        assert!(matches!(
+            decide(&chain, 1, &[Some(false), None, None]),
+            Err(Error::Rejected)
+        ));
+        let chain = entries(
+            r#"authenticators = [
+                { name = "totp", control = "requisite" },
+                { name = "email" },
+            ]"#,
+        );
+        assert!(matches!(
+            decide(&chain, 1, &[Some(false), Some(true)]),
+            Err(Error::Rejected)
+        ));
+    }
+
+    #[test]
+    fn code_formats_of_enabled_authenticators() {
+        let chain = entries(
+            r#"authenticators = [
+                { name = "bypass" },
+                { name = "hotp" },
+                { name = "totp" },
+                { name = "yubico" },
+                { name = "backup_codes" },
+            ]"#,
+        );
+        let mut configuration = SecRcCfg::default();
+        configuration.totp_secret = Some(SecretString::from(String::from("JBSWY3DPEHPK3PXP")));
+        configuration.backup_codes_file = Some(String::from("/nonexistent"));
+        let lens: Vec<usize> = code_formats(&configuration, &chain)
+            .iter()
+            .map(|format| format.len)
+            .collect();
+        assert_eq!(lens, [6, auth_backup::CODE_LEN]);
+    }
+}
@@ -0,0 +1,220 @@
+//
+//  Copyright (C) 2026 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::auth_totp::hash_from_name;
+use crate::chain::REGISTRY;
+use crate::config::{self, CommandLine, FactorWeight, FileKind, LogTarget, SecRcCfg};
+use crate::logging;
+use cidr::IpCidr;
+use nix::unistd::{AccessFlags, access};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use totp_rs::Secret;
+
+/// Check that a file could be opened for appending, without creating it
+fn check_writable_file(path: &str) -> Result<(), io::Error> {
+    let path = Path::new(path);
+    if path.exists() {
+        Ok(access(path, AccessFlags::W_OK)?)
+    } else {
+        // It would be created in its parent
+        check_writable_dir(path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+}
+
+/// Check that files could be created in a directory, or in its closest
+/// existing ancestor if it would be created
+fn check_writable_dir(path: &Path) -> Result<(), io::Error> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("."));
+    if !existing.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("{} is not a directory", existing.display()),
+        ));
+    }
+    Ok(access(existing, AccessFlags::W_OK | AccessFlags::X_OK)?)
+}
+
+/// Check that the chain only has known authenticators and can grant access
+fn check_chain(configuration: &SecRcCfg, problems: &mut Vec<String>) {
+    let chain = configuration
+        .chain
+        .as_ref()
+        .expect("Bug: `config.chain` should never be `None`");
+    let entries = chain
+        .authenticators
+        .as_ref()
+        .expect("Bug: `config.chain.authenticators` should never be `None`");
+    let is_known = |name: &str| REGISTRY.iter().any(|(known, _)| *known == name);
+    for entry in entries {
+        if !is_known(&entry.name) {
+            problems.push(format!(
+                "chain.authenticators: unknown authenticator {:?}",
+                entry.name
+            ));
+        }
+    }
+    let required_factors = chain
+        .required_factors
+        .expect("Bug: `config.chain.required_factors` should never be `None`");
+    if required_factors == 0 {
+        problems.push(String::from("chain.required_factors: must be at least 1"));
+        return;
+    }
+    let counted: BTreeSet<&str> = entries
+        .iter()
+        .filter(|entry| entry.counts_as == FactorWeight::One && is_known(&entry.name))
+        .map(|entry| entry.name.as_str())
+        .collect();
+    let grants_all = entries
+        .iter()
+        .any(|entry| entry.counts_as == FactorWeight::All);
+    if !grants_all && counted.len() < required_factors as usize {
+        problems.push(format!(
+            "chain.required_factors: {required_factors} factors required but only {} authenticator(s) count as one",
+            counted.len()
+        ));
+    }
+}
+
+/// Check the values of the merged configuration
+fn check_values(configuration: &SecRcCfg, problems: &mut Vec<String>) {
+    for network in configuration.accepted_ips.iter().flatten() {
+        if let Err(e) = network.parse::<IpCidr>() {
+            problems.push(format!("accepted_ips: bad CIDR {network:?}: {e}"));
+        }
+    }
+    for (option, hash) in [
+        ("totp_hash", &configuration.totp_hash),
+        ("hotp_hash", &configuration.hotp_hash),
+    ] {
+        if let Some(hash) = hash
+            && hash_from_name(hash).is_none()
+        {
+            problems.push(format!("{option}: invalid hash {hash:?}"));
+        }
+    }
+    for (option, secret) in [
+        ("totp_secret", &configuration.totp_secret),
+        ("hotp_secret", &configuration.hotp_secret),
+    ] {
+        if let Some(secret) = secret
+            && Secret::Encoded(secret.expose().to_string())
+                .to_bytes()
+                .is_err()
+        {
+            problems.push(format!("{option}: not valid base32"));
+        }
+    }
+    if let Some(Err(e)) = configuration
+        .mail_passwdcmd
+        .as_ref()
+        .map(CommandLine::to_args)
+    {
+        problems.push(format!("mail_passwdcmd: {e}"));
+    }
+    if let Err(e) = configuration.shell_args() {
+        problems.push(format!("shell_args: {e}"));
+    }
+    match configuration.shell() {
+        None => problems.push(String::from("shell: not set")),
+        Some(shell) => match config::search_shells(shell) {
+            Ok(true) => (),
+            Ok(false) => problems.push(format!("shell: {shell:?} is not listed in /etc/shells")),
+            Err(e) => problems.push(format!("shell: cannot read /etc/shells: {e}")),
+        },
+    }
+    match (configuration.log_target(), configuration.log_file()) {
+        (LogTarget::File, None) => problems.push(String::from("log_file: not set")),
+        (LogTarget::File, Some(log_file)) => {
+            if let Err(e) = check_writable_file(log_file) {
+                problems.push(format!("log_file: {log_file:?} is not writable: {e}"));
+            }
+        }
+        (target, _) => {
+            if let Err(e) = logging::open(configuration, target) {
+                problems.push(format!("log_target: {e}"));
+            }
+        }
+    }
+    if let Some(tmpdir) = &configuration.tmpdir
+        && let Err(e) = check_writable_dir(Path::new(tmpdir))
+    {
+        problems.push(format!("tmpdir: {tmpdir:?} is not writable: {e}"));
+    }
+    let state_path = configuration.state_path();
+    if let Err(e) = check_writable_dir(&state_path) {
+        problems.push(format!(
+            "state_dir: {} is not writable: {e}",
+            state_path.display()
+        ));
+    }
+    if let Ok(metadata) = fs::metadata(&state_path)
+        && let Some(problem) = config::insecure_modes(&metadata, FileKind::Secret)
+    {
+        problems.push(format!("state_dir: {}: {problem}", state_path.display()));
+    }
+    if let Some(backup_codes_file) = &configuration.backup_codes_file
+        && let Ok(metadata) = fs::metadata(backup_codes_file)
+        && let Some(problem) = config::insecure_modes(&metadata, FileKind::Secret)
+    {
+        problems.push(format!("backup_codes_file: {problem}"));
+    }
+    check_chain(configuration, problems);
+}
+
+/// Load every configuration file and check the result, printing all
+/// problems found. Returns whether there is none.
+pub fn check_config(user_file: Option<PathBuf>) -> bool {
+    let mut problems: Vec<String> = Vec::new();
+    let mut configuration = SecRcCfg::default();
+    configuration.set_user_file(user_file);
+    let mut found_any = false;
+    for (scope, path) in configuration.config_paths() {
+        let result = configuration.load_config(&path, scope);
+        if matches!(&result, Err(e) if e.is_not_found()) {
+            continue;
+        }
+        found_any = true;
+        println!("Checking {}", path.display());
+        match result {
+            Ok(warnings) => problems.extend(warnings.iter().map(ToString::to_string)),
+            Err(e) => problems.push(e.to_string()),
+        }
+    }
+    if !found_any {
+        problems.push(String::from("No configuration file found"));
+    }
+    problems.extend(configuration.load_env().iter().map(ToString::to_string));
+    check_values(&configuration, &mut problems);
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+    } else {
+        println!("{} problem(s) found", problems.len());
+    }
+    problems.is_empty()
+}
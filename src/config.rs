@@ -17,15 +17,53 @@
 //  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
 //
 
+use crate::chain::REGISTRY;
 use crate::ip::get_from;
+use crate::secret::SecretString;
+use cidr::IpCidr;
 use log::warn;
+use nix::unistd::{Group, getegid, geteuid, getgroups};
 use serde::Deserialize;
-use std::fs::File;
-use std::fs::OpenOptions;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, Metadata, OpenOptions};
 use std::io;
 use std::io::prelude::*;
+use std::iter;
+use std::net::IpAddr;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// Resolve each of the secrets of `$target` from its `_file` or `_cmd` variant
+macro_rules! resolve_secrets {
+    ($self:ident, $target:expr, $warnings:ident, $($field:ident, $file:ident, $cmd:ident);* $(;)?) => {
+        $(
+            $self.resolve_secret(
+                stringify!($field),
+                &mut $target.$field,
+                $target.$file.take(),
+                $target.$cmd.take(),
+                &mut $warnings,
+            )?;
+        )*
+    };
+}
+
+/// Set each of the fields to the incoming value if it is not `None`
+macro_rules! override_some {
+    ($self:ident, $incoming:ident, $($field:ident),* $(,)?) => {
+        $(
+            if $incoming.$field.is_some() {
+                $self.$field = $incoming.$field;
+            }
+        )*
+    };
+}
 
 /// Type for deserializing a secrc.toml
 /// Representing a sib secure shell configuration
@@ -35,18 +73,238 @@ pub struct SecRcCfg {
     pub accepted_ips: Option<Vec<String>>,
     pub email: Option<String>,
     shell: Option<String>,
-    shell_args: Option<String>,
+    shell_args: Option<CommandLine>,
     log_file: Option<String>,
+    log_format: Option<LogFormat>,
+    log_target: Option<LogTarget>,
+    syslog_socket: Option<String>,
+    log_secrets: Option<bool>,
+    pause_on_error: Option<bool>,
     pub tmpdir: Option<String>,
+    state_dir: Option<String>,
+    pub lockout_threshold: Option<u32>,
+    pub lockout_window: Option<u64>,
+    pub lockout_delay: Option<u64>,
     pub mail_host: Option<String>,
     pub mail_port: Option<u16>,
     pub mail_from: Option<String>,
-    pub mail_passwdcmd: Option<String>,
-    pub totp_secret: Option<String>,
+    pub mail_passwdcmd: Option<CommandLine>,
+    pub totp_secret: Option<SecretString>,
+    totp_secret_file: Option<String>,
+    totp_secret_cmd: Option<CommandLine>,
     pub totp_digits: Option<u32>,
     pub totp_timestep: Option<u64>,
     pub totp_hash: Option<String>,
+    pub hotp_secret: Option<SecretString>,
+    hotp_secret_file: Option<String>,
+    hotp_secret_cmd: Option<CommandLine>,
+    pub hotp_digits: Option<u32>,
+    pub hotp_hash: Option<String>,
+    pub hotp_window: Option<u64>,
     pub yubico_id: Option<String>,
+    pub yubico_client_id: Option<u64>,
+    pub yubico_api_key: Option<SecretString>,
+    yubico_api_key_file: Option<String>,
+    yubico_api_key_cmd: Option<CommandLine>,
+    pub yubico_servers: Option<Vec<String>>,
+    pub yubico_sl: Option<u8>,
+    pub yubico_timeout: Option<u64>,
+    pub yubico_aes_key: Option<SecretString>,
+    yubico_aes_key_file: Option<String>,
+    yubico_aes_key_cmd: Option<CommandLine>,
+    pub yubico_private_id: Option<SecretString>,
+    yubico_private_id_file: Option<String>,
+    yubico_private_id_cmd: Option<CommandLine>,
+    pub yubico_keys: Option<Vec<YubicoKey>>,
+    pub backup_codes_file: Option<String>,
+    pub chain: Option<ChainCfg>,
+    strict_keys: Option<StrictKeys>,
+    locked: Option<Vec<String>>,
+    strict_modes: Option<StrictModes>,
+    #[serde(rename = "match")]
+    matches: Option<MatchCfg>,
+    include: Option<Vec<String>>,
+    env_overrides: Option<Vec<String>>,
+    /// Replaces the user files if set
+    #[serde(skip)]
+    user_file: Option<PathBuf>,
+}
+
+/// Sections of the system-wide configuration overriding the settings for
+/// some logins only, like `Match` blocks of sshd
+#[derive(Deserialize, Debug, Default)]
+struct MatchCfg {
+    /// Keyed by CIDR of the source IP
+    #[serde(default)]
+    ip: BTreeMap<String, SecRcCfg>,
+    /// Keyed by group name
+    #[serde(default)]
+    group: BTreeMap<String, SecRcCfg>,
+    /// Keyed by user name
+    #[serde(default)]
+    user: BTreeMap<String, SecRcCfg>,
+}
+
+/// Format of the records in `log_file`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, for machine consumption
+    Json,
+}
+
+/// Where the log records are sent
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    /// Append to `log_file`
+    File,
+    /// Send to the syslog daemon through `syslog_socket`
+    Syslog,
+    /// Send to systemd-journald with structured fields
+    Journald,
+}
+
+impl fmt::Display for LogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::File => "file",
+            Self::Syslog => "syslog",
+            Self::Journald => "journald",
+        })
+    }
+}
+
+/// Which configuration files are rejected if they contain unknown keys
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StrictKeys {
+    /// Unknown keys only produce warnings
+    None,
+    /// Only system-wide files are checked strictly
+    System,
+    /// Both system-wide and user files are checked strictly
+    All,
+}
+
+/// How files with insecure ownership or permissions are treated,
+/// like `StrictModes` of OpenSSH
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StrictModes {
+    /// Refuse to use the file
+    Yes,
+    /// Use the file but log a warning
+    Warn,
+    /// Do not check
+    No,
+}
+
+/// What a file is used for, deciding who may own and access it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// Owned by root and not writable by anyone else
+    SystemConfig,
+    /// Owned by the user or root and not writable by anyone else
+    UserConfig,
+    /// Owned by the user or root and not accessible by anyone else
+    Secret,
+}
+
+/// Whether a configuration file is system-wide or per-user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    System,
+    User,
+}
+
+/// A command line, either as an array of arguments or as a string split
+/// with POSIX shell quoting rules
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum CommandLine {
+    Args(Vec<String>),
+    Quoted(String),
+}
+
+impl CommandLine {
+    /// Get the arguments, including the program name if any
+    pub fn to_args(&self) -> Result<Vec<String>, Error> {
+        match self {
+            Self::Args(args) => Ok(args.clone()),
+            Self::Quoted(command_line) => shlex::split(command_line)
+                .ok_or_else(|| Error::InvalidCommandLine(command_line.clone())),
+        }
+    }
+}
+
+/// A security key in `yubico_keys`
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct YubicoKey {
+    /// Only the first 12 characters matter
+    pub id: String,
+    /// Name used in log messages
+    pub label: Option<String>,
+    /// Hex-encoded AES key for offline validation
+    pub aes_key: Option<SecretString>,
+    /// Resolved into `aes_key` when loaded
+    pub aes_key_file: Option<String>,
+    pub aes_key_cmd: Option<CommandLine>,
+    /// Hex-encoded private ID for offline validation
+    pub private_id: Option<SecretString>,
+    /// Resolved into `private_id` when loaded
+    pub private_id_file: Option<String>,
+    pub private_id_cmd: Option<CommandLine>,
+}
+
+/// Type for the `[chain]` section
+/// Determining which authenticators are tried and in which order
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChainCfg {
+    pub authenticators: Option<Vec<ChainEntry>>,
+    /// Number of factors from distinct authenticators needed to grant access
+    pub required_factors: Option<u32>,
+}
+
+/// An authenticator in the chain
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChainEntry {
+    /// Name of the authenticator as registered in `chain::REGISTRY`
+    pub name: String,
+    #[serde(default)]
+    pub control: Control,
+    #[serde(default)]
+    pub counts_as: FactorWeight,
+}
+
+/// PAM-style control flag of a chain entry
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Control {
+    /// Acceptance is counted, rejection moves on to the next entry
+    #[default]
+    Sufficient,
+    /// Acceptance is counted, rejection denies access
+    Requisite,
+    /// Must accept, rejection or skipping denies access
+    Required,
+    /// Acceptance is counted, rejection is ignored
+    Optional,
+}
+
+/// How many factors an accepting chain entry counts as
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FactorWeight {
+    /// Does not count towards `required_factors`
+    Zero,
+    /// Counts as one factor
+    #[default]
+    One,
+    /// Grants access regardless of `required_factors` and required entries
+    All,
 }
 
 /// Error type for the configuration
@@ -54,107 +312,632 @@ pub struct SecRcCfg {
 pub enum Error {
     #[error("no configuration found")]
     MissingConfig,
+    #[error("cannot find home directory")]
+    MissingHome,
     #[error("invalid config: {0}")]
     InvalidConfig(String),
-    #[error("IO error")]
+    #[error("IO error: {0}")]
     Io(#[from] io::Error),
+    #[error("unbalanced quotes in {0:?}")]
+    InvalidCommandLine(String),
     #[error("non-standard shell")]
     NonStandardShell,
     #[error("cannot execute shell")]
     ShellExec(io::Error),
     #[error("cannot parse TOML: {0}")]
     TomlParse(#[from] toml::de::Error),
+    #[error("unknown keys: {}", .0.join(", "))]
+    UnknownKeys(Vec<String>),
+    #[error("`{0}` is only honored in system-wide files")]
+    SystemOnly(&'static str),
+    #[error("ignored keys locked by the system-wide configuration: {}", .0.join(", "))]
+    Locked(Vec<&'static str>),
+    #[error("insecure file: {0}")]
+    InsecureModes(String),
+    #[error("included again by itself")]
+    IncludeCycle,
+    #[error("bad include pattern {0:?}: {1}")]
+    IncludePattern(String, glob::PatternError),
+    #[error("only one of `{0}`, `{0}_file` and `{0}_cmd` can be set")]
+    SecretConflict(&'static str),
+    #[error("cannot get `{0}`: {1}")]
+    SecretSource(&'static str, Box<Error>),
+    #[error("in `{0}`: {1}")]
+    InEnv(String, Box<Error>),
+    #[error("{}: {source}", .path.display())]
+    InFile {
+        path: PathBuf,
+        #[source]
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// The error without the file it happened in
+    fn root_cause(&self) -> &Self {
+        match self {
+            Self::InFile { source, .. } => source.root_cause(),
+            _ => self,
+        }
+    }
+
+    /// Whether this error is caused by a nonexistent file
+    pub fn is_not_found(&self) -> bool {
+        matches!(self.root_cause(), Self::Io(e) if e.kind() == io::ErrorKind::NotFound)
+    }
+
+    /// Whether this error is caused by insecure ownership or permissions
+    fn is_insecure_modes(&self) -> bool {
+        matches!(self.root_cause(), Self::InsecureModes(_))
+    }
 }
 
 impl SecRcCfg {
-    /// Parse and load a configuration file in TOML format at `FILE_PATH`
-    pub fn load_config(&mut self, file_path: &str) -> Result<(), Error> {
-        let mut file = File::open(file_path)?;
+    /// Parse and load a configuration file in TOML format at `FILE_PATH`.
+    /// Unknown keys are an error if the file is checked strictly, otherwise
+    /// they are returned as warnings along with other ignored settings.
+    pub fn load_config(&mut self, file_path: &Path, scope: Scope) -> Result<Vec<Error>, Error> {
+        self.load_config_from(file_path, scope, &mut Vec::new())
+    }
+
+    /// Like `load_config`, `including` being the files whose includes are
+    /// being loaded
+    fn load_config_from(
+        &mut self,
+        file_path: &Path,
+        scope: Scope,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<Vec<Error>, Error> {
+        let in_file = |source: Error| Error::InFile {
+            path: file_path.to_path_buf(),
+            source: Box::new(source),
+        };
+        let canonical_path = fs::canonicalize(file_path).map_err(|e| in_file(e.into()))?;
+        if including.contains(&canonical_path) {
+            return Err(in_file(Error::IncludeCycle));
+        }
+        let mut file = File::open(file_path).map_err(|e| in_file(e.into()))?;
+        let metadata = file.metadata().map_err(|e| in_file(e.into()))?;
+        let mut warnings = Vec::new();
+        let kind = match scope {
+            Scope::System => FileKind::SystemConfig,
+            Scope::User => FileKind::UserConfig,
+        };
+        warnings.extend(
+            self.check_modes(&metadata, kind)
+                .map_err(in_file)?
+                .map(in_file),
+        );
         let mut file_content = String::new();
-        file.read_to_string(&mut file_content)?;
-        let mut toml_content: Self = toml::from_str(&file_content)?;
-        // Override the current value if the incoming one is not `None`
-        if let Some(incoming_accepted_ips) = &mut toml_content.accepted_ips {
-            if let Some(accepted_ips) = self.accepted_ips.as_mut() {
-                accepted_ips.append(incoming_accepted_ips);
-            } else {
-                self.accepted_ips = toml_content.accepted_ips;
+        file.read_to_string(&mut file_content)
+            .map_err(|e| in_file(e.into()))?;
+        let (mut toml_content, mut unknown_keys) =
+            Self::parse_config(&file_content).map_err(in_file)?;
+        toml_content.validate().map_err(in_file)?;
+        if scope == Scope::User && toml_content.has_secrets() {
+            warnings.extend(
+                self.check_modes(&metadata, FileKind::Secret)
+                    .map_err(in_file)?
+                    .map(in_file),
+            );
+        }
+        // Before checking for locked keys so that the variants are locked too
+        warnings.extend(
+            self.resolve_secrets(&mut toml_content)
+                .map_err(in_file)?
+                .into_iter()
+                .map(in_file),
+        );
+        let strict = match scope {
+            Scope::System => {
+                // A typo here would leave the setting unlocked
+                unknown_keys.extend(toml_content.unknown_key_lists());
+                for section in toml_content.matches.iter().flat_map(MatchCfg::sections) {
+                    unknown_keys.extend(section.unknown_key_lists());
+                    if section.matches.is_some() {
+                        warnings.push(in_file(Error::InvalidConfig(String::from(
+                            "nested `match` sections are ignored",
+                        ))));
+                    }
+                }
+                // A system-wide file may relax the check for itself
+                toml_content.strict_keys.or(self.strict_keys) != Some(StrictKeys::None)
+            }
+            Scope::User => {
+                warnings.extend(
+                    self.restrict_user(&mut toml_content)
+                        .into_iter()
+                        .map(in_file),
+                );
+                self.strict_keys() == StrictKeys::All
+            }
+        };
+        if !unknown_keys.is_empty() {
+            if strict {
+                return Err(in_file(Error::UnknownKeys(unknown_keys)));
+            }
+            warnings.push(in_file(Error::UnknownKeys(unknown_keys)));
+        }
+        let mut matching = match toml_content.matches.take() {
+            Some(matches) => matches.matching().map_err(in_file)?,
+            None => Vec::new(),
+        };
+        for section in &mut matching {
+            warnings.extend(
+                self.resolve_secrets(section)
+                    .map_err(in_file)?
+                    .into_iter()
+                    .map(in_file),
+            );
+        }
+        let includes = toml_content.include.take();
+        self.merge(toml_content);
+        // Included files override the including one
+        if let Some(patterns) = includes {
+            including.push(canonical_path);
+            let result = self.load_includes(file_path, &patterns, scope, including);
+            including.pop();
+            warnings.append(&mut result?);
+        }
+        // Matching sections are the most specific, so they come last
+        for section in matching {
+            self.merge_section(section);
+        }
+        Ok(warnings)
+    }
+
+    /// Load the files matching each of the glob `patterns` in lexicographic
+    /// order, relative patterns being relative to the including file
+    fn load_includes(
+        &mut self,
+        file_path: &Path,
+        patterns: &[String],
+        scope: Scope,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<Vec<Error>, Error> {
+        let mut warnings = Vec::new();
+        let base_dir = file_path.parent().unwrap_or_else(|| Path::new("/"));
+        for pattern in patterns {
+            let pattern = base_dir.join(pattern).to_string_lossy().into_owned();
+            let mut paths = glob::glob(&pattern)
+                .map_err(|e| Error::IncludePattern(pattern.clone(), e))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::from)?;
+            paths.sort();
+            for path in paths {
+                warnings.append(&mut self.load_config_from(&path, scope, including)?);
             }
         }
-        if toml_content.email.is_some() {
-            self.email = toml_content.email;
+        Ok(warnings)
+    }
+
+    /// Replace the `_file` and `_cmd` variants of the secrets of an incoming
+    /// configuration with their values, returning warnings about the files
+    fn resolve_secrets(&self, incoming: &mut Self) -> Result<Vec<Error>, Error> {
+        let mut warnings = Vec::new();
+        resolve_secrets!(
+            self, incoming, warnings,
+            totp_secret, totp_secret_file, totp_secret_cmd;
+            hotp_secret, hotp_secret_file, hotp_secret_cmd;
+            yubico_api_key, yubico_api_key_file, yubico_api_key_cmd;
+            yubico_aes_key, yubico_aes_key_file, yubico_aes_key_cmd;
+            yubico_private_id, yubico_private_id_file, yubico_private_id_cmd;
+        );
+        for key in incoming.yubico_keys.iter_mut().flatten() {
+            resolve_secrets!(
+                self, key, warnings,
+                aes_key, aes_key_file, aes_key_cmd;
+                private_id, private_id_file, private_id_cmd;
+            );
+        }
+        Ok(warnings)
+    }
+
+    /// Set `secret` from the file or the output of the command if any,
+    /// at most one of them being allowed
+    fn resolve_secret(
+        &self,
+        name: &'static str,
+        secret: &mut Option<SecretString>,
+        file: Option<String>,
+        cmd: Option<CommandLine>,
+        warnings: &mut Vec<Error>,
+    ) -> Result<(), Error> {
+        let resolved = match (file, cmd) {
+            (None, None) => return Ok(()),
+            (Some(file), None) => self.read_secret_file(Path::new(&file), warnings),
+            (None, Some(cmd)) => run_secret_cmd(&cmd),
+            (Some(_), Some(_)) => return Err(Error::SecretConflict(name)),
+        };
+        if secret.is_some() {
+            return Err(Error::SecretConflict(name));
+        }
+        *secret = Some(resolved.map_err(|e| Error::SecretSource(name, Box::new(e)))?);
+        Ok(())
+    }
+
+    /// Read a secret from a file only accessible by the user
+    fn read_secret_file(
+        &self,
+        path: &Path,
+        warnings: &mut Vec<Error>,
+    ) -> Result<SecretString, Error> {
+        let mut file = File::open(path)?;
+        warnings.extend(self.check_modes(&file.metadata()?, FileKind::Secret)?);
+        let mut content = Zeroizing::new(String::new());
+        file.read_to_string(&mut content)?;
+        Ok(SecretString::from(content.trim_end().to_string()))
+    }
+
+    /// Unset what an incoming user configuration is not allowed to set,
+    /// returning what was ignored
+    fn restrict_user(&mut self, incoming: &mut Self) -> Vec<Error> {
+        let mut ignored = Vec::new();
+        if incoming.strict_keys.take().is_some() {
+            ignored.push(Error::SystemOnly("strict_keys"));
         }
-        if toml_content.shell.is_some() {
-            self.shell = toml_content.shell;
+        if incoming.locked.take().is_some() {
+            ignored.push(Error::SystemOnly("locked"));
         }
-        if toml_content.shell_args.is_some() {
-            self.shell_args = toml_content.shell_args;
+        if incoming.strict_modes.take().is_some() {
+            ignored.push(Error::SystemOnly("strict_modes"));
         }
-        if toml_content.log_file.is_some() {
-            self.log_file = toml_content.log_file;
+        if incoming.matches.take().is_some() {
+            ignored.push(Error::SystemOnly("match"));
         }
-        if toml_content.tmpdir.is_some() {
-            self.tmpdir = toml_content.tmpdir;
+        if incoming.env_overrides.take().is_some() {
+            ignored.push(Error::SystemOnly("env_overrides"));
         }
-        if toml_content.mail_host.is_some() {
-            self.mail_host = toml_content.mail_host;
+        if incoming.log_secrets.take().is_some() {
+            ignored.push(Error::SystemOnly("log_secrets"));
         }
-        if toml_content.mail_port.is_some() {
-            self.mail_port = toml_content.mail_port;
+        let locked = self.take_locked(incoming);
+        if !locked.is_empty() {
+            ignored.push(Error::Locked(locked));
         }
-        if toml_content.mail_from.is_some() {
-            self.mail_from = toml_content.mail_from;
+        ignored
+    }
+
+    /// Entries of `locked` and `env_overrides` that are not known keys
+    fn unknown_key_lists(&self) -> impl Iterator<Item = String> {
+        let locked = self.locked.iter().flatten().map(|key| (key, "locked"));
+        let env_overrides = self
+            .env_overrides
+            .iter()
+            .flatten()
+            .map(|key| (key, "env_overrides"));
+        locked
+            .chain(env_overrides)
+            .filter(|(key, _)| !Self::LOCKABLE_KEYS.contains(&key.as_str()))
+            .map(|(key, list)| format!("{key} (in `{list}`)"))
+    }
+
+    /// Take the values of the keys in `env_overrides` from the
+    /// `SIBSECSH_<KEY>` environment variables, like a user file.
+    /// Each variable is taken on its own, so that a bad one is only
+    /// returned as a warning without affecting the others.
+    pub fn load_env(&mut self) -> Vec<Error> {
+        self.load_env_from(|name| env::var(name).ok())
+    }
+
+    /// Like `load_env`, getting the variables with `var`
+    fn load_env_from(&mut self, var: impl Fn(&str) -> Option<String>) -> Vec<Error> {
+        let mut warnings = Vec::new();
+        for key in self.env_overrides.clone().into_iter().flatten() {
+            let name = format!("SIBSECSH_{}", key.to_uppercase());
+            let Some(value) = var(&name) else {
+                continue;
+            };
+            // Only `key` is taken so that the value cannot set other keys
+            let parsed = format!("{key} = {value}")
+                .parse::<toml::Table>()
+                .ok()
+                .and_then(|mut parsed| parsed.remove(&key));
+            // Anything that is not a TOML value of the right type is taken
+            // as a string
+            let incoming = match parsed {
+                Some(parsed) if !parsed.is_str() => {
+                    Self::from_env_value(&key, parsed).or_else(|e| {
+                        Self::from_env_value(&key, toml::Value::String(value)).map_err(|_| e)
+                    })
+                }
+                Some(parsed) => Self::from_env_value(&key, parsed),
+                None => Self::from_env_value(&key, toml::Value::String(value)),
+            };
+            match incoming {
+                Ok(mut incoming) => {
+                    let locked = self.take_locked(&mut incoming);
+                    if !locked.is_empty() {
+                        warnings.push(Error::InEnv(name, Box::new(Error::Locked(locked))));
+                    }
+                    self.merge(incoming);
+                }
+                Err(e) => warnings.push(Error::InEnv(name, Box::new(e))),
+            }
         }
-        if toml_content.mail_passwdcmd.is_some() {
-            self.mail_passwdcmd = toml_content.mail_passwdcmd;
+        warnings
+    }
+
+    /// A configuration with only `key` set to `value`
+    fn from_env_value(key: &str, value: toml::Value) -> Result<Self, Error> {
+        let mut table = toml::Table::new();
+        table.insert(key.to_string(), value);
+        let incoming: Self = table.try_into()?;
+        incoming.validate()?;
+        Ok(incoming)
+    }
+
+    /// Parse a configuration in TOML format, also returning the paths of
+    /// the keys that are not recognized
+    pub fn parse_config(file_content: &str) -> Result<(Self, Vec<String>), Error> {
+        let deserializer = toml::de::Deserializer::parse(file_content)?;
+        let mut unknown_keys: Vec<String> = Vec::new();
+        let toml_content = serde_ignored::deserialize(deserializer, |path| {
+            // `Option`s show up as `?` segments
+            unknown_keys.push(path.to_string().replace("?.", ""));
+        })?;
+        Ok((toml_content, unknown_keys))
+    }
+
+    /// Reject values that can never make sense, including those in the
+    /// `match` sections
+    fn validate(&self) -> Result<(), Error> {
+        let chains = iter::once(self)
+            .chain(self.matches.iter().flat_map(MatchCfg::sections))
+            .filter_map(|config| config.chain.as_ref());
+        for chain in chains {
+            if chain.required_factors == Some(0) {
+                // Would grant access without any factor
+                return Err(Error::InvalidConfig(String::from(
+                    "`chain.required_factors` must be at least 1",
+                )));
+            }
         }
-        if toml_content.totp_secret.is_some() {
-            self.totp_secret = toml_content.totp_secret;
+        Ok(())
+    }
+
+    /// Override the current values with the incoming ones that are not `None`
+    pub fn merge(&mut self, mut incoming: Self) {
+        if let Some(incoming_accepted_ips) = &mut incoming.accepted_ips {
+            if let Some(accepted_ips) = self.accepted_ips.as_mut() {
+                accepted_ips.append(incoming_accepted_ips);
+            } else {
+                self.accepted_ips = incoming.accepted_ips;
+            }
         }
-        if toml_content.totp_digits.is_some() {
-            self.totp_digits = toml_content.totp_digits;
+        if let Some(incoming_locked) = &mut incoming.locked {
+            if let Some(locked) = self.locked.as_mut() {
+                locked.append(incoming_locked);
+            } else {
+                self.locked = incoming.locked;
+            }
         }
-        if toml_content.totp_timestep.is_some() {
-            self.totp_timestep = toml_content.totp_timestep;
+        if let Some(incoming_env_overrides) = &mut incoming.env_overrides {
+            if let Some(env_overrides) = self.env_overrides.as_mut() {
+                env_overrides.append(incoming_env_overrides);
+            } else {
+                self.env_overrides = incoming.env_overrides;
+            }
         }
-        if toml_content.totp_hash.is_some() {
-            self.totp_hash = toml_content.totp_hash;
+        if let Some(incoming_chain) = incoming.chain {
+            if let Some(chain) = self.chain.as_mut() {
+                chain.merge(incoming_chain);
+            } else {
+                self.chain = Some(incoming_chain);
+            }
         }
-        if toml_content.yubico_id.is_some() {
-            self.yubico_id = toml_content.yubico_id;
+        override_some!(
+            self,
+            incoming,
+            email,
+            shell,
+            shell_args,
+            log_file,
+            log_format,
+            log_target,
+            syslog_socket,
+            tmpdir,
+            state_dir,
+            lockout_threshold,
+            lockout_window,
+            lockout_delay,
+            mail_host,
+            mail_port,
+            mail_from,
+            mail_passwdcmd,
+            totp_secret,
+            totp_digits,
+            totp_timestep,
+            totp_hash,
+            hotp_secret,
+            hotp_digits,
+            hotp_hash,
+            hotp_window,
+            yubico_id,
+            yubico_client_id,
+            yubico_api_key,
+            yubico_servers,
+            yubico_sl,
+            yubico_timeout,
+            yubico_aes_key,
+            yubico_private_id,
+            yubico_keys,
+            backup_codes_file,
+            strict_keys,
+            strict_modes,
+            log_secrets,
+            pause_on_error,
+        );
+    }
+
+    /// Merge a matching `match` section, whose `accepted_ips` replace the
+    /// current ones rather than adding to them
+    fn merge_section(&mut self, mut section: Self) {
+        if section.accepted_ips.is_some() {
+            self.accepted_ips = section.accepted_ips.take();
+        }
+        self.merge(section);
+    }
+
+    /// Whether secrets are set inline in this configuration
+    fn has_secrets(&self) -> bool {
+        self.totp_secret.is_some()
+            || self.hotp_secret.is_some()
+            || self.yubico_api_key.is_some()
+            || self.yubico_aes_key.is_some()
+            || self.yubico_private_id.is_some()
+            || self
+                .yubico_keys
+                .iter()
+                .flatten()
+                .any(|key| key.aes_key.is_some() || key.private_id.is_some())
+    }
+
+    /// Apply `strict_modes` to the ownership and permissions of a file,
+    /// returning the problem if it is only to be warned about
+    pub fn check_modes(&self, metadata: &Metadata, kind: FileKind) -> Result<Option<Error>, Error> {
+        let Some(problem) = insecure_modes(metadata, kind) else {
+            return Ok(None);
+        };
+        match self
+            .strict_modes
+            .expect("Bug: `SecRcCfg.strict_modes` should never be `None`")
+        {
+            StrictModes::Yes => Err(Error::InsecureModes(problem)),
+            StrictModes::Warn => Ok(Some(Error::InsecureModes(problem))),
+            StrictModes::No => Ok(None),
+        }
+    }
+
+    /// Check that a file holding secrets is only accessible by the user.
+    /// A missing file is fine.
+    pub fn check_secret_file(&self, path: &Path) -> Result<(), Error> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(warning) = self.check_modes(&metadata, FileKind::Secret)? {
+            warn!("{}: {warning}", path.display());
         }
         Ok(())
     }
 
-    /// Load configuration from all designated locations, latter overriding former ones
-    pub fn load_all_possible(&mut self) -> Result<(), Error> {
+    /// Use `user_file` instead of the user files
+    pub fn set_user_file(&mut self, user_file: Option<PathBuf>) {
+        self.user_file = user_file;
+    }
+
+    /// The user file changed by subcommands
+    pub fn user_config_path(&self) -> Result<PathBuf, Error> {
+        match &self.user_file {
+            Some(user_file) => Ok(user_file.clone()),
+            None => Ok(home::home_dir()
+                .ok_or(Error::MissingHome)?
+                .join(".secrc.toml")),
+        }
+    }
+
+    /// All designated configuration locations, latter overriding former ones
+    pub fn config_paths(&self) -> Vec<(Scope, PathBuf)> {
+        let mut paths = vec![
+            (Scope::System, PathBuf::from("/etc/secrc")),
+            (Scope::System, PathBuf::from("/etc/secrc.toml")),
+        ];
+        if let Some(user_file) = &self.user_file {
+            paths.push((Scope::User, user_file.clone()));
+        } else if let Some(home_dir) = home::home_dir() {
+            paths.push((Scope::User, home_dir.join(".secrc")));
+            paths.push((Scope::User, home_dir.join(".secrc.toml")));
+        }
+        paths
+    }
+
+    /// Load configuration from all designated locations, latter overriding former ones,
+    /// then from the environment.
+    /// A problem with a strictly checked file is an error, other problems
+    /// are returned as warnings.
+    pub fn load_all_possible(&mut self) -> Result<Vec<Error>, Error> {
+        let mut warnings = Vec::new();
         // A warning will be emitted if no configuration is found
         let mut found_any = false;
 
-        for filename in &["/etc/secrc", "/etc/secrc.toml"] {
-            if self.load_config(filename).is_ok() {
-                found_any = true;
-            }
-        }
-        if let Some(mut home_dir) = home::home_dir() {
-            home_dir.push(".secrc");
-            if let Some(path_str) = home_dir.to_str()
-                && self.load_config(path_str).is_ok()
-            {
-                found_any = true;
-            }
-            home_dir.set_extension("toml");
-            if let Some(path_str) = home_dir.to_str()
-                && self.load_config(path_str).is_ok()
-            {
-                found_any = true;
+        for (scope, path) in self.config_paths() {
+            let strict = match scope {
+                Scope::System => self.strict_keys() != StrictKeys::None,
+                Scope::User => self.strict_keys() == StrictKeys::All,
+            };
+            match self.load_config(&path, scope) {
+                Ok(mut file_warnings) => {
+                    found_any = true;
+                    warnings.append(&mut file_warnings);
+                }
+                Err(e) if e.is_not_found() => (),
+                Err(e) if strict || e.is_insecure_modes() => return Err(e),
+                Err(e) => warnings.push(e),
             }
         }
-        if found_any {
-            Ok(())
-        } else {
-            Err(Error::MissingConfig)
+        if !found_any {
+            warnings.push(Error::MissingConfig);
         }
+        warnings.append(&mut self.load_env());
+        Ok(warnings)
+    }
+
+    /// Which files are rejected if they contain unknown keys
+    fn strict_keys(&self) -> StrictKeys {
+        self.strict_keys
+            .expect("Bug: `SecRcCfg.strict_keys` should never be `None`")
+    }
+
+    /// The configured shell
+    pub fn shell(&self) -> Option<&str> {
+        self.shell.as_deref()
+    }
+
+    /// Arguments always passed to the shell
+    pub fn shell_args(&self) -> Result<Vec<String>, Error> {
+        self.shell_args
+            .as_ref()
+            // self.shell_args must not be None as guaranteed by Default-initialization
+            .expect("Bug: `SecRcCfg.shell_args` should never be `None`")
+            .to_args()
+    }
+
+    /// The configured log file
+    pub fn log_file(&self) -> Option<&str> {
+        self.log_file.as_deref()
+    }
+
+    /// The format of the records in the log file
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+            .expect("Bug: `SecRcCfg.log_format` should never be `None`")
+    }
+
+    /// Where the log records are sent
+    pub fn log_target(&self) -> LogTarget {
+        self.log_target
+            .expect("Bug: `SecRcCfg.log_target` should never be `None`")
+    }
+
+    /// The socket of the syslog daemon
+    pub fn syslog_socket(&self) -> &str {
+        self.syslog_socket
+            .as_ref()
+            .expect("Bug: `SecRcCfg.syslog_socket` should never be `None`")
+    }
+
+    /// Whether submitted codes are logged as is, for troubleshooting only
+    pub fn log_secrets(&self) -> bool {
+        self.log_secrets
+            .expect("Bug: `SecRcCfg.log_secrets` should never be `None`")
+    }
+
+    /// Whether to wait for Enter after an error on a terminal
+    pub fn pause_on_error(&self) -> bool {
+        self.pause_on_error
+            .expect("Bug: `SecRcCfg.pause_on_error` should never be `None`")
     }
 
     /// Open the log file specified in the config in append mode
@@ -167,16 +950,19 @@ impl SecRcCfg {
         )?)
     }
 
+    /// Directory for persistent state such as failure counts
+    pub fn state_path(&self) -> PathBuf {
+        PathBuf::from(
+            self.state_dir
+                .as_ref()
+                .or(self.tmpdir.as_ref())
+                .expect("Bug: `SecRcCfg.tmpdir` should never be `None`"),
+        )
+    }
+
     /// Execute the configured shell, replacing the current process
     pub fn execute_shell(&self, mut additional_params: Vec<String>) -> Result<(), Error> {
-        let mut args: Vec<String> = self
-            .shell_args
-            .as_ref()
-            // self.shell_args must not be None as guaranteed by Default-initialization
-            .expect("Bug: `SecRcCfg.shell_args` should never be `None`")
-            .split_whitespace()
-            .map(ToString::to_string)
-            .collect();
+        let mut args: Vec<String> = self.shell_args()?;
         args.append(&mut additional_params);
         let shell = self.shell.as_ref().ok_or_else(|| {
             Error::InvalidConfig(String::from("`SecRcCfg.shell` should not be `None`"))
@@ -187,7 +973,7 @@ impl SecRcCfg {
             Ok(true) => (),
             Err(e) => warn!("Cannot search for shells: {e:?}"),
         }
-        let err = std::process::Command::new(shell)
+        let err = Command::new(shell)
             .args(&args)
             .env("SIB_FROM_IP", get_from())
             .exec();
@@ -200,20 +986,36 @@ impl Default for SecRcCfg {
         // This is a fallback since it may not be safe, other users can read it
         // The best practice is to set it in the configuration
         let mut tmpdir = String::from("/tmp/sibsecsh");
-        if let Some(mut home_dir) = home::home_dir() {
-            home_dir.push(".cache/sibsecsh");
-            if let Some(path_string) = home_dir.to_str() {
+        let mut backup_codes_file = None;
+        if let Some(home_dir) = home::home_dir() {
+            if let Some(path_string) = home_dir.join(".cache/sibsecsh").to_str() {
                 tmpdir = path_string.to_string();
             }
+            backup_codes_file = home_dir
+                .join(".secrc_codes.toml")
+                .to_str()
+                .map(ToString::to_string);
         }
 
         Self {
             accepted_ips: Some(vec![]),
             shell: None,
             // Default to have no args
-            shell_args: Some(String::default()),
+            shell_args: Some(CommandLine::Args(Vec::new())),
             log_file: Some(String::from("/var/log/sibsecsh.log")),
+            log_format: Some(LogFormat::Text),
+            log_target: Some(LogTarget::File),
+            syslog_socket: Some(String::from("/dev/log")),
+            // Never log submitted codes unless debugging
+            log_secrets: Some(false),
+            pause_on_error: Some(true),
             tmpdir: Some(tmpdir),
+            // None means `tmpdir`
+            state_dir: None,
+            // 0 disables lockout
+            lockout_threshold: Some(5),
+            lockout_window: Some(900),
+            lockout_delay: Some(30),
             // None disables this authenticator
             // Not prefixed by `mail_` for compatibility reason
             email: None,
@@ -223,17 +1025,346 @@ impl Default for SecRcCfg {
             mail_passwdcmd: None,
             // None disables this authenticator
             totp_secret: None,
+            totp_secret_file: None,
+            totp_secret_cmd: None,
             totp_digits: Some(6),
             totp_timestep: Some(30),
             totp_hash: Some(String::from("SHA1")),
             // None disables this authenticator
+            hotp_secret: None,
+            hotp_secret_file: None,
+            hotp_secret_cmd: None,
+            hotp_digits: Some(6),
+            hotp_hash: Some(String::from("SHA1")),
+            hotp_window: Some(10),
+            // None disables this authenticator
             // Only the first 12 characters matter
             yubico_id: None,
+            // Required to use the Yubico authenticator
+            yubico_client_id: None,
+            // Base64-encoded. None disables signing
+            yubico_api_key: None,
+            yubico_api_key_file: None,
+            yubico_api_key_cmd: None,
+            yubico_servers: Some(vec![String::from(
+                "https://api.yubico.com/wsapi/2.0/verify",
+            )]),
+            // None leaves it to the server
+            yubico_sl: None,
+            yubico_timeout: Some(10),
+            // None uses the validation servers
+            yubico_aes_key: None,
+            yubico_aes_key_file: None,
+            yubico_aes_key_cmd: None,
+            yubico_private_id: None,
+            yubico_private_id_file: None,
+            yubico_private_id_cmd: None,
+            // In addition to `yubico_id`
+            yubico_keys: None,
+            // The authenticator is disabled if this file does not exist
+            backup_codes_file,
+            chain: Some(ChainCfg::default()),
+            // A typo in the system-wide configuration must not go unnoticed
+            strict_keys: Some(StrictKeys::System),
+            // Nothing is locked unless the system-wide configuration says so
+            locked: Some(vec![]),
+            strict_modes: Some(StrictModes::Yes),
+            // Only taken from system-wide files when they are loaded
+            matches: None,
+            // Only followed when the including file is loaded
+            include: None,
+            // Nothing can be overridden unless the system-wide configuration says so
+            env_overrides: Some(vec![]),
+            user_file: None,
+        }
+    }
+}
+
+impl ChainCfg {
+    /// Override the current value if the incoming one is not `None`
+    fn merge(&mut self, incoming: Self) {
+        if incoming.authenticators.is_some() {
+            self.authenticators = incoming.authenticators;
+        }
+        if incoming.required_factors.is_some() {
+            self.required_factors = incoming.required_factors;
+        }
+    }
+
+    /// Take only what makes the chain stricter from the incoming value:
+    /// a higher `required_factors` and new required entries, which never
+    /// count as a factor so that they cannot stand in for a mandated one.
+    /// Returns whether anything was ignored.
+    fn merge_stricter(&mut self, incoming: Self) -> bool {
+        let mut ignored = false;
+        if let Some(required_factors) = incoming.required_factors {
+            if self
+                .required_factors
+                .is_none_or(|current| required_factors >= current)
+            {
+                self.required_factors = Some(required_factors);
+            } else {
+                ignored = true;
+            }
+        }
+        let authenticators = self.authenticators.get_or_insert_with(Vec::new);
+        for mut entry in incoming.authenticators.into_iter().flatten() {
+            if entry.control == Control::Required
+                && entry.counts_as != FactorWeight::All
+                && !authenticators.iter().any(|known| known.name == entry.name)
+            {
+                entry.counts_as = FactorWeight::Zero;
+                authenticators.push(entry);
+            } else {
+                ignored = true;
+            }
         }
+        ignored
     }
 }
 
-fn search_shells(shell_name: &str) -> io::Result<bool> {
+impl MatchCfg {
+    /// All sections regardless of whether they match
+    fn sections(&self) -> impl Iterator<Item = &SecRcCfg> {
+        self.ip
+            .values()
+            .chain(self.group.values())
+            .chain(self.user.values())
+    }
+
+    /// The sections that apply to the current login, from the least to the
+    /// most specific: source IP, then group, then user
+    fn matching(self) -> Result<Vec<SecRcCfg>, Error> {
+        let mut matching = Vec::new();
+        let from_ip: Option<IpAddr> = get_from().parse().ok();
+        for (network, section) in self.ip {
+            let cidr: IpCidr = network.parse().map_err(|_| {
+                Error::InvalidConfig(format!("bad CIDR in `match.ip`: {network:?}"))
+            })?;
+            if from_ip.is_some_and(|ip| cidr.contains(&ip)) {
+                matching.push(section);
+            }
+        }
+        let groups = current_groups();
+        for (group, section) in self.group {
+            if groups.contains(&group) {
+                matching.push(section);
+            }
+        }
+        let username = whoami::username().ok();
+        for (user, section) in self.user {
+            if username.as_ref() == Some(&user) {
+                matching.push(section);
+            }
+        }
+        Ok(matching)
+    }
+}
+
+/// Names of the groups of the current process
+fn current_groups() -> Vec<String> {
+    let mut gids = getgroups().unwrap_or_default();
+    gids.push(getegid());
+    gids.into_iter()
+        .filter_map(|gid| Group::from_gid(gid).ok().flatten())
+        .map(|group| group.name)
+        .collect()
+}
+
+/// List the keys that can be locked by the system-wide configuration
+macro_rules! lockable {
+    ($($field:ident),* $(,)?) => {
+        impl SecRcCfg {
+            /// Keys accepted in `locked`
+            const LOCKABLE_KEYS: &[&str] = &[$(stringify!($field)),*];
+
+            /// Unset the fields of an incoming user configuration that are
+            /// locked, returning their names. Factors can still be added to
+            /// a locked chain.
+            fn take_locked(&mut self, incoming: &mut Self) -> Vec<&'static str> {
+                let locked = self.locked.clone().unwrap_or_default();
+                let is_locked = |key: &str| locked.iter().any(|locked_key| locked_key == key);
+                let mut taken = Vec::new();
+                if is_locked("chain")
+                    && let Some(incoming_chain) = incoming.chain.take()
+                    && self
+                        .chain
+                        .as_mut()
+                        .expect("Bug: `SecRcCfg.chain` should never be `None`")
+                        .merge_stricter(incoming_chain)
+                {
+                    taken.push("chain");
+                }
+                $(
+                    if is_locked(stringify!($field)) && incoming.$field.take().is_some() {
+                        taken.push(stringify!($field));
+                    }
+                )*
+                taken
+            }
+        }
+    };
+}
+
+lockable!(
+    accepted_ips,
+    email,
+    shell,
+    shell_args,
+    log_file,
+    log_format,
+    log_target,
+    syslog_socket,
+    pause_on_error,
+    tmpdir,
+    state_dir,
+    lockout_threshold,
+    lockout_window,
+    lockout_delay,
+    mail_host,
+    mail_port,
+    mail_from,
+    mail_passwdcmd,
+    totp_secret,
+    totp_digits,
+    totp_timestep,
+    totp_hash,
+    hotp_secret,
+    hotp_digits,
+    hotp_hash,
+    hotp_window,
+    yubico_id,
+    yubico_client_id,
+    yubico_api_key,
+    yubico_servers,
+    yubico_sl,
+    yubico_timeout,
+    yubico_aes_key,
+    yubico_private_id,
+    yubico_keys,
+    backup_codes_file,
+    chain,
+);
+
+impl Default for ChainCfg {
+    fn default() -> Self {
+        // Every known authenticator, in the order they were historically tried
+        let authenticators = REGISTRY
+            .iter()
+            .map(|(name, _)| ChainEntry {
+                name: name.to_string(),
+                control: Control::Sufficient,
+                // Including `bypass` and `local_ip`, which the example
+                // warns about next to `required_factors`
+                counts_as: FactorWeight::One,
+            })
+            .collect();
+        Self {
+            authenticators: Some(authenticators),
+            // The first accepting authenticator grants access
+            required_factors: Some(1),
+        }
+    }
+}
+
+/// Read a user configuration file, a missing one being empty
+fn read_user_file(path: &Path) -> Result<String, Error> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check that the secret `key` can be set inline in the user configuration
+/// file at `path`, that is neither `<key>_file` nor `<key>_cmd` is set there
+pub fn check_user_secret(path: &Path, key: &'static str) -> Result<(), Error> {
+    let table: toml::Table = toml::from_str(&read_user_file(path)?)?;
+    if table.contains_key(&format!("{key}_file")) || table.contains_key(&format!("{key}_cmd")) {
+        return Err(Error::SecretConflict(key));
+    }
+    Ok(())
+}
+
+/// Set the top-level `key` in the user configuration file at `path` to the
+/// string `value`, keeping everything else intact. Returns the path of the file.
+pub fn set_user_value(path: PathBuf, key: &str, value: &str) -> Result<PathBuf, Error> {
+    let content = read_user_file(&path)?;
+    let new_line = format!("{key} = {}", toml::Value::String(value.to_string()));
+    let mut lines: Vec<&str> = content.lines().collect();
+    // Top-level keys end at the first table header
+    let top_level_end = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    let existing = lines[..top_level_end].iter().position(|line| {
+        line.split_once('=')
+            .is_some_and(|(line_key, _)| line_key.trim() == key)
+    });
+    match existing {
+        Some(index) => lines[index] = &new_line,
+        None => lines.insert(top_level_end, &new_line),
+    }
+    let mut new_content = lines.join("\n");
+    new_content.push('\n');
+    // Never leave a broken configuration behind
+    let new_config: SecRcCfg = toml::from_str(&new_content)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    // `mode` only applies to a new file
+    if new_config.has_secrets() {
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(new_content.as_bytes())?;
+    Ok(path)
+}
+
+/// Get a secret from the standard output of a command
+fn run_secret_cmd(cmd: &CommandLine) -> Result<SecretString, Error> {
+    let args = cmd.to_args()?;
+    let (program, program_args) = args
+        .split_first()
+        .ok_or_else(|| Error::InvalidConfig(String::from("empty command")))?;
+    let output = Command::new(program)
+        .args(program_args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        return Err(Error::InvalidConfig(format!(
+            "{program:?} failed with {}",
+            output.status
+        )));
+    }
+    let secret = std::str::from_utf8(&stdout)
+        .map_err(|_| Error::InvalidConfig(format!("output of {program:?} is not UTF-8")))?;
+    Ok(SecretString::from(secret.trim_end().to_string()))
+}
+
+/// Describe what is wrong with the ownership or permissions of a file, if anything
+pub fn insecure_modes(metadata: &Metadata, kind: FileKind) -> Option<String> {
+    let owner = metadata.uid();
+    let owner_allowed = match kind {
+        FileKind::SystemConfig => owner == 0,
+        FileKind::UserConfig | FileKind::Secret => owner == 0 || owner == geteuid().as_raw(),
+    };
+    if !owner_allowed {
+        return Some(format!("owned by uid {owner}"));
+    }
+    let mode = metadata.mode() & 0o7777;
+    if kind == FileKind::Secret {
+        (mode & 0o077 != 0).then(|| format!("accessible by group or others (mode {mode:04o})"))
+    } else {
+        (mode & 0o022 != 0).then(|| format!("writable by group or others (mode {mode:04o})"))
+    }
+}
+
+pub fn search_shells(shell_name: &str) -> io::Result<bool> {
     const SHELLS_FILE: &str = "/etc/shells";
     let mut shells_content = String::new();
     let mut found = false;
@@ -245,3 +1376,379 @@ fn search_shells(shell_name: &str) -> io::Result<bool> {
     }
     Ok(found)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[derive(Deserialize)]
+    struct Command {
+        cmd: CommandLine,
+    }
+
+    fn args(toml: &str) -> Result<Vec<String>, Error> {
+        toml::from_str::<Command>(toml).unwrap().cmd.to_args()
+    }
+
+    #[test]
+    fn command_line_array() {
+        assert_eq!(
+            args(r#"cmd = ["pass", "show", "a b"]"#).unwrap(),
+            ["pass", "show", "a b"]
+        );
+        assert!(args("cmd = []").unwrap().is_empty());
+    }
+
+    #[test]
+    fn command_line_quoted() {
+        assert_eq!(
+            args(r#"cmd = "cat '/home/user/mail password.txt'""#).unwrap(),
+            ["cat", "/home/user/mail password.txt"]
+        );
+        assert_eq!(
+            args(r#"cmd = "sh -c \"echo \\\"$HOME\\\"\"""#).unwrap(),
+            ["sh", "-c", r#"echo "$HOME""#]
+        );
+        assert!(args(r#"cmd = """#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn command_line_unbalanced_quote() {
+        assert!(matches!(
+            args(r#"cmd = "cat 'file""#),
+            Err(Error::InvalidCommandLine(command_line)) if command_line == "cat 'file"
+        ));
+    }
+
+    fn chain(toml: &str) -> ChainCfg {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn names(chain: &ChainCfg) -> Vec<(&str, FactorWeight)> {
+        chain
+            .authenticators
+            .iter()
+            .flatten()
+            .map(|entry| (entry.name.as_str(), entry.counts_as))
+            .collect()
+    }
+
+    #[test]
+    fn merge_stricter_raises_required_factors() {
+        let mut current = chain("required_factors = 2");
+        assert!(!current.merge_stricter(chain("required_factors = 3")));
+        assert_eq!(current.required_factors, Some(3));
+        assert!(current.merge_stricter(chain("required_factors = 1")));
+        assert_eq!(current.required_factors, Some(3));
+    }
+
+    #[test]
+    fn merge_stricter_adds_required_entries_as_zero() {
+        let mut current = chain(r#"authenticators = [{ name = "totp" }]"#);
+        let ignored = current.merge_stricter(chain(
+            r#"authenticators = [
+                { name = "local_ip", control = "required", counts_as = "one" },
+                { name = "yubico", control = "required" },
+            ]"#,
+        ));
+        assert!(!ignored);
+        assert_eq!(
+            names(&current),
+            [
+                ("totp", FactorWeight::One),
+                ("local_ip", FactorWeight::Zero),
+                ("yubico", FactorWeight::Zero),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_stricter_ignores_weakening_entries() {
+        let mut current = chain(r#"authenticators = [{ name = "totp" }]"#);
+        for incoming in [
+            r#"authenticators = [{ name = "bypass", counts_as = "all", control = "required" }]"#,
+            r#"authenticators = [{ name = "email", control = "sufficient" }]"#,
+            r#"authenticators = [{ name = "hotp", control = "optional" }]"#,
+            r#"authenticators = [{ name = "totp", control = "required" }]"#,
+        ] {
+            assert!(current.merge_stricter(chain(incoming)), "{incoming}");
+        }
+        assert_eq!(names(&current), [("totp", FactorWeight::One)]);
+    }
+
+    fn config(toml: &str) -> SecRcCfg {
+        let mut config = SecRcCfg::default();
+        config.merge(toml::from_str(toml).unwrap());
+        config
+    }
+
+    #[test]
+    fn take_locked_unsets_locked_fields() {
+        let mut system = config(r#"locked = ["shell", "accepted_ips"]"#);
+        let mut user: SecRcCfg = toml::from_str(
+            r#"
+            shell = "/bin/sh"
+            accepted_ips = ["0.0.0.0/0"]
+            email = "user@example.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(system.take_locked(&mut user), ["accepted_ips", "shell"]);
+        assert!(user.shell.is_none());
+        assert!(user.accepted_ips.is_none());
+        assert_eq!(user.email.as_deref(), Some("user@example.com"));
+    }
+
+    #[test]
+    fn take_locked_keeps_stricter_chain() {
+        let mut system = config(
+            r#"
+            locked = ["chain"]
+            [chain]
+            required_factors = 1
+            authenticators = [{ name = "totp" }]
+            "#,
+        );
+        let mut user: SecRcCfg = toml::from_str(
+            r#"
+            [chain]
+            required_factors = 2
+            authenticators = [{ name = "yubico", control = "required" }]
+            "#,
+        )
+        .unwrap();
+        assert!(system.take_locked(&mut user).is_empty());
+        assert!(user.chain.is_none());
+        let chain = system.chain.as_ref().unwrap();
+        assert_eq!(chain.required_factors, Some(2));
+        assert_eq!(
+            names(chain),
+            [("totp", FactorWeight::One), ("yubico", FactorWeight::Zero)]
+        );
+
+        let mut user: SecRcCfg = toml::from_str(
+            r#"
+            [chain]
+            required_factors = 1
+            authenticators = [{ name = "bypass", counts_as = "all" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(system.take_locked(&mut user), ["chain"]);
+        assert_eq!(names(system.chain.as_ref().unwrap()).len(), 2);
+    }
+
+    #[test]
+    fn set_user_value_restricts_existing_file() {
+        let path = env::temp_dir().join(format!("sibsecsh-test-{}-secrc.toml", process::id()));
+        fs::write(&path, "shell = \"/bin/sh\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        set_user_value(path.clone(), "totp_secret", "JBSWY3DPEHPK3PXP").unwrap();
+        let mode = fs::metadata(&path).unwrap().mode() & 0o777;
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(mode, 0o600);
+        assert_eq!(
+            content,
+            "shell = \"/bin/sh\"\ntotp_secret = \"JBSWY3DPEHPK3PXP\"\n"
+        );
+    }
+
+    #[test]
+    fn private_ids_are_secrets() {
+        let config: SecRcCfg = toml::from_str(r#"yubico_private_id = "8792ebfe26cc""#).unwrap();
+        assert!(config.has_secrets());
+        let config: SecRcCfg = toml::from_str(
+            r#"yubico_keys = [{ id = "ccccccdteffu", private_id = "8792ebfe26cc" }]"#,
+        )
+        .unwrap();
+        assert!(config.has_secrets());
+        let config: SecRcCfg =
+            toml::from_str(r#"yubico_keys = [{ id = "ccccccdteffu" }]"#).unwrap();
+        assert!(!config.has_secrets());
+    }
+
+    #[test]
+    fn match_section_replaces_accepted_ips() {
+        let mut config = config(r#"accepted_ips = ["192.168.1.0/24"]"#);
+        config.merge(toml::from_str(r#"accepted_ips = ["10.0.0.0/8"]"#).unwrap());
+        assert_eq!(
+            config.accepted_ips.as_deref().unwrap(),
+            ["192.168.1.0/24", "10.0.0.0/8"]
+        );
+        config.merge_section(toml::from_str(r#"accepted_ips = ["10.1.0.0/16"]"#).unwrap());
+        assert_eq!(config.accepted_ips.as_deref().unwrap(), ["10.1.0.0/16"]);
+        config.merge_section(toml::from_str("accepted_ips = []").unwrap());
+        assert!(config.accepted_ips.as_deref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn match_sections_override_includes() {
+        let dir = env::temp_dir().join(format!("sibsecsh-test-{}-include", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("included.toml"),
+            "lockout_threshold = 7\nlockout_delay = 60\n",
+        )
+        .unwrap();
+        let user = whoami::username().unwrap();
+        fs::write(
+            dir.join("secrc.toml"),
+            format!(
+                "include = [\"included.toml\"]\nlockout_threshold = 1\n\n[match.user.{user}]\nlockout_threshold = 3\n"
+            ),
+        )
+        .unwrap();
+        let mut config = SecRcCfg {
+            strict_modes: Some(StrictModes::No),
+            ..SecRcCfg::default()
+        };
+        let result = config.load_config(&dir.join("secrc.toml"), Scope::System);
+        fs::remove_dir_all(&dir).ok();
+        assert!(result.unwrap().is_empty());
+        assert_eq!(config.lockout_threshold, Some(3));
+        assert_eq!(config.lockout_delay, Some(60));
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let dir = env::temp_dir().join(format!("sibsecsh-test-{}-cycle", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.toml"), "include = [\"b.toml\"]\n").unwrap();
+        fs::write(dir.join("b.toml"), "include = [\"a.toml\"]\n").unwrap();
+        let mut config = SecRcCfg {
+            strict_modes: Some(StrictModes::No),
+            ..SecRcCfg::default()
+        };
+        let result = config.load_config(&dir.join("a.toml"), Scope::System);
+        fs::remove_dir_all(&dir).ok();
+        match result {
+            Err(Error::InFile { path, source }) => {
+                assert_eq!(path.file_name(), Some("a.toml".as_ref()));
+                assert!(matches!(*source, Error::IncludeCycle), "{source}");
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn glob_includes_load_in_order() {
+        let dir = env::temp_dir().join(format!("sibsecsh-test-{}-glob", process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(
+            dir.join("secrc.toml"),
+            "include = [\"conf.d/*.toml\"]\nlockout_threshold = 1\nlockout_delay = 1\ntotp_digits = 7\n",
+        )
+        .unwrap();
+        // Written out of order so that the directory order does not help
+        fs::write(dir.join("conf.d/20-b.toml"), "lockout_threshold = 20\n").unwrap();
+        fs::write(
+            dir.join("conf.d/10-a.toml"),
+            "lockout_threshold = 10\nlockout_delay = 10\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/ignored.conf"), "lockout_threshold = 99\n").unwrap();
+        let mut config = SecRcCfg {
+            strict_modes: Some(StrictModes::No),
+            ..SecRcCfg::default()
+        };
+        let result = config.load_config(&dir.join("secrc.toml"), Scope::System);
+        fs::remove_dir_all(&dir).ok();
+        assert!(result.unwrap().is_empty());
+        assert_eq!(config.lockout_threshold, Some(20));
+        assert_eq!(config.lockout_delay, Some(10));
+        assert_eq!(config.totp_digits, Some(7));
+    }
+
+    fn env_config(system: &str, vars: &[(&str, &str)]) -> (SecRcCfg, Vec<Error>) {
+        let mut configuration = config(system);
+        let warnings = configuration.load_env_from(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| (*value).to_string())
+        });
+        (configuration, warnings)
+    }
+
+    #[test]
+    fn env_only_overrides_allowed_keys() {
+        let (config, warnings) = env_config(
+            r#"env_overrides = ["totp_digits"]"#,
+            &[("SIBSECSH_TOTP_DIGITS", "8"), ("SIBSECSH_HOTP_DIGITS", "8")],
+        );
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(config.totp_digits, Some(8));
+        assert_eq!(config.hotp_digits, Some(6));
+    }
+
+    #[test]
+    fn env_values_fall_back_to_strings() {
+        let (config, warnings) = env_config(
+            r#"env_overrides = ["email", "accepted_ips", "shell_args"]"#,
+            &[
+                ("SIBSECSH_EMAIL", "123"),
+                ("SIBSECSH_ACCEPTED_IPS", r#"["10.0.0.0/8"]"#),
+                ("SIBSECSH_SHELL_ARGS", "-l -i"),
+            ],
+        );
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(config.email.as_deref(), Some("123"));
+        assert_eq!(
+            config.accepted_ips.as_deref(),
+            Some([String::from("10.0.0.0/8")].as_slice())
+        );
+        assert_eq!(config.shell_args().unwrap(), ["-l", "-i"]);
+    }
+
+    #[test]
+    fn bad_env_value_keeps_the_others() {
+        let (config, warnings) = env_config(
+            r#"env_overrides = ["totp_digits", "email"]"#,
+            &[
+                ("SIBSECSH_TOTP_DIGITS", "eight"),
+                ("SIBSECSH_EMAIL", "user@example.com"),
+            ],
+        );
+        assert!(
+            matches!(warnings.as_slice(), [Error::InEnv(name, _)] if name == "SIBSECSH_TOTP_DIGITS"),
+            "{warnings:?}"
+        );
+        assert_eq!(config.totp_digits, Some(6));
+        assert_eq!(config.email.as_deref(), Some("user@example.com"));
+    }
+
+    #[test]
+    fn env_cannot_set_other_keys() {
+        let (config, _) = env_config(
+            r#"env_overrides = ["totp_digits"]"#,
+            &[("SIBSECSH_TOTP_DIGITS", "8\nshell = \"/bin/sh\"")],
+        );
+        assert_eq!(config.totp_digits, Some(8));
+        assert_eq!(config.shell(), SecRcCfg::default().shell());
+    }
+
+    #[test]
+    fn env_cannot_override_locked_keys() {
+        let (config, warnings) = env_config(
+            r#"
+            env_overrides = ["shell", "email"]
+            locked = ["shell"]
+            "#,
+            &[
+                ("SIBSECSH_SHELL", "/bin/sh"),
+                ("SIBSECSH_EMAIL", "user@example.com"),
+            ],
+        );
+        assert!(
+            matches!(
+                warnings.as_slice(),
+                [Error::InEnv(name, source)]
+                    if name == "SIBSECSH_SHELL" && matches!(**source, Error::Locked(ref keys) if keys == &["shell"])
+            ),
+            "{warnings:?}"
+        );
+        assert_eq!(config.shell(), SecRcCfg::default().shell());
+        assert_eq!(config.email.as_deref(), Some("user@example.com"));
+    }
+}
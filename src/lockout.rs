@@ -0,0 +1,262 @@
+//
+//  Copyright (C) 2021 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::config::SecRcCfg;
+use crate::state::{Error, LockedState};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STATE_FILE: &str = "lockout.toml";
+
+/// Recent failures of a user or a source IP
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+struct Record {
+    failures: u32,
+    /// UNIX timestamp of the latest failure
+    last_failure: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct LockoutState {
+    #[serde(default)]
+    users: BTreeMap<String, Record>,
+    #[serde(default)]
+    ips: BTreeMap<String, Record>,
+}
+
+/// A failed attempt recorded before the user answers, so that
+/// disconnecting at a prompt still counts. Dropping it keeps the failure.
+#[must_use]
+pub struct Attempt<'a> {
+    lockout: &'a Lockout<'a>,
+}
+
+impl Attempt<'_> {
+    /// The attempt did not fail after all, such as a correct code or a skip
+    pub fn forget(self) {
+        if let Err(e) = self.lockout.forget_failure() {
+            error!("Cannot forget failed attempt: {e}");
+        }
+    }
+}
+
+/// Failed-attempt lockout shared across sessions
+pub struct Lockout<'a> {
+    config: &'a SecRcCfg,
+    user: &'a str,
+    ip: &'a str,
+    threshold: u32,
+    window: u64,
+    delay: u64,
+    /// Whether recording a failed attempt has failed
+    unrecorded: Cell<bool>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+impl<'a> Lockout<'a> {
+    pub fn new(config: &'a SecRcCfg, user: &'a str, ip: &'a str) -> Self {
+        Lockout {
+            config,
+            user,
+            ip,
+            threshold: config
+                .lockout_threshold
+                .expect("Bug: `config.lockout_threshold` should never be `None`"),
+            window: config
+                .lockout_window
+                .expect("Bug: `config.lockout_window` should never be `None`"),
+            delay: config
+                .lockout_delay
+                .expect("Bug: `config.lockout_delay` should never be `None`"),
+            unrecorded: Cell::new(false),
+        }
+    }
+
+    /// Run `f` on the locked state and save it afterwards
+    fn update<T>(&self, f: impl FnOnce(&mut LockoutState) -> T) -> Result<T, Error> {
+        let state = LockedState::open_state(self.config, STATE_FILE)?;
+        let mut lockout_state: LockoutState = state.read()?;
+        let result = f(&mut lockout_state);
+        state.write(&lockout_state)?;
+        Ok(result)
+    }
+
+    /// Seconds before `record` may try again, doubling with every failure
+    /// past the threshold but never exceeding the window
+    fn remaining_for(&self, record: &Record, now: u64) -> Option<u64> {
+        if record.failures < self.threshold {
+            return None;
+        }
+        let backoff = 2_u64.saturating_pow(record.failures - self.threshold);
+        let until = record
+            .last_failure
+            .saturating_add(self.delay.saturating_mul(backoff).min(self.window));
+        (until > now).then(|| until - now)
+    }
+
+    /// Seconds the user or the source IP is still locked out for, if any
+    pub fn remaining(&self) -> Result<Option<u64>, Error> {
+        if self.threshold == 0 {
+            return Ok(None);
+        }
+        let now = now();
+        let state = LockedState::open_state(self.config, STATE_FILE)?;
+        let lockout_state: LockoutState = state.read()?;
+        let user_record = lockout_state.users.get(self.user);
+        let ip_record = lockout_state.ips.get(self.ip);
+        Ok(user_record
+            .into_iter()
+            .chain(ip_record)
+            .filter_map(|record| self.remaining_for(record, now))
+            .max())
+    }
+
+    /// Count a failed attempt for the user and the source IP
+    fn record_failure(&self) -> Result<(), Error> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+        let now = now();
+        let window = self.window;
+        let count = |record: &mut Record| {
+            // Failures older than the window are forgotten
+            if now.saturating_sub(record.last_failure) > window {
+                record.failures = 0;
+            }
+            record.failures = record.failures.saturating_add(1);
+            record.last_failure = now;
+        };
+        self.update(|lockout_state| {
+            count(
+                lockout_state
+                    .users
+                    .entry(self.user.to_string())
+                    .or_default(),
+            );
+            // Most likely a local login
+            if !self.ip.is_empty() {
+                count(lockout_state.ips.entry(self.ip.to_string()).or_default());
+            }
+        })
+    }
+
+    /// Count an attempt as failed until it is known not to be
+    pub fn begin_attempt(&self) -> Attempt<'_> {
+        if let Err(e) = self.record_failure() {
+            error!("Cannot record failed attempt: {e}");
+            self.unrecorded.set(true);
+        }
+        Attempt { lockout: self }
+    }
+
+    /// Whether every failed attempt so far has been recorded, access being
+    /// denied otherwise
+    pub fn recorded_all(&self) -> bool {
+        !self.unrecorded.get()
+    }
+
+    /// Take back one failed attempt of the user and the source IP
+    fn forget_failure(&self) -> Result<(), Error> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+        let uncount = |record: Option<&mut Record>| {
+            if let Some(record) = record {
+                record.failures = record.failures.saturating_sub(1);
+            }
+        };
+        self.update(|lockout_state| {
+            uncount(lockout_state.users.get_mut(self.user));
+            uncount(lockout_state.ips.get_mut(self.ip));
+        })
+    }
+
+    /// Forget the failures of the user and the source IP after a success
+    pub fn reset(&self) -> Result<(), Error> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+        self.update(|lockout_state| {
+            lockout_state.users.remove(self.user);
+            lockout_state.ips.remove(self.ip);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lockout, Record};
+    use crate::config::SecRcCfg;
+
+    fn config() -> SecRcCfg {
+        let mut config = SecRcCfg::default();
+        config.lockout_threshold = Some(3);
+        config.lockout_window = Some(900);
+        config.lockout_delay = Some(30);
+        config
+    }
+
+    #[test]
+    fn below_threshold_is_not_locked() {
+        let config = config();
+        let lockout = Lockout::new(&config, "user", "");
+        let record = Record {
+            failures: 2,
+            last_failure: 1000,
+        };
+        assert_eq!(lockout.remaining_for(&record, 1000), None);
+    }
+
+    #[test]
+    fn delay_doubles_past_threshold() {
+        let config = config();
+        let lockout = Lockout::new(&config, "user", "");
+        for (failures, delay) in [(3, 30), (4, 60), (5, 120), (6, 240)] {
+            let record = Record {
+                failures,
+                last_failure: 1000,
+            };
+            assert_eq!(lockout.remaining_for(&record, 1000), Some(delay));
+            assert_eq!(lockout.remaining_for(&record, 1010), Some(delay - 10));
+            assert_eq!(lockout.remaining_for(&record, 1000 + delay), None);
+        }
+    }
+
+    #[test]
+    fn delay_is_capped_by_window() {
+        let config = config();
+        let lockout = Lockout::new(&config, "user", "");
+        for failures in [8, 40, u32::MAX] {
+            let record = Record {
+                failures,
+                last_failure: 1000,
+            };
+            assert_eq!(lockout.remaining_for(&record, 1000), Some(900));
+            assert_eq!(lockout.remaining_for(&record, 1900), None);
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//
+//  Copyright (C) 2026 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::audit::{self, JsonLogger};
+use crate::config::{self, LogFormat, LogTarget, SecRcCfg};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::Value as JsonValue;
+use simplelog::{
+    ColorChoice, CombinedLogger, Config, ConfigBuilder, SharedLogger, TermLogger, TerminalMode,
+    WriteLogger,
+};
+use std::io;
+use std::iter;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use thiserror::Error;
+
+/// Socket of the native protocol of systemd-journald
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// Tag of the records sent to syslog and journald
+const IDENTIFIER: &str = "sibsecsh";
+/// `LOG_USER`, for free-form messages
+const FACILITY_USER: u8 = 1;
+/// `LOG_AUTHPRIV`, for authentication events
+const FACILITY_AUTHPRIV: u8 = 10;
+/// Targets tried when the configured one is unavailable, in this order
+const FALLBACK_ORDER: [LogTarget; 3] = [LogTarget::File, LogTarget::Journald, LogTarget::Syslog];
+
+/// Error type for setting up the log targets
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("cannot open log file: {0}")]
+    File(#[from] config::Error),
+    #[error("cannot connect to syslog at {0}: {1}")]
+    Syslog(String, io::Error),
+    #[error("cannot connect to journald: {0}")]
+    Journald(io::Error),
+    #[error("logging to {0} instead")]
+    Fallback(LogTarget),
+    #[error("no log target is available, logging to the terminal only")]
+    NoTarget,
+}
+
+/// Syslog severity of a log level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Syslog facility of a record
+fn facility(record: &Record) -> u8 {
+    if audit::is_event(record) {
+        FACILITY_AUTHPRIV
+    } else {
+        FACILITY_USER
+    }
+}
+
+/// Connect a datagram socket to `path`
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Append a field in the native journal protocol
+fn append_field(payload: &mut Vec<u8>, name: &str, value: &str) {
+    payload.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Multi-line values need an explicit length
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value.as_bytes());
+    payload.push(b'\n');
+}
+
+/// Logger sending every record to the syslog daemon
+#[derive(Debug)]
+pub struct SyslogLogger {
+    level: LevelFilter,
+    format: LogFormat,
+    socket: UnixDatagram,
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = match self.format {
+            LogFormat::Text => record.args().to_string(),
+            LogFormat::Json => JsonValue::Object(audit::record_fields(record)).to_string(),
+        };
+        // The daemon adds the time of reception
+        let priority = facility(record) * 8 + severity(record.level());
+        let line = format!("<{priority}>{IDENTIFIER}[{}]: {message}", process::id());
+        self.socket.send(line.as_bytes()).ok();
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for SyslogLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+/// Logger sending every record to systemd-journald with the audit fields
+/// prefixed by `SIBSECSH_`
+#[derive(Debug)]
+pub struct JournaldLogger {
+    level: LevelFilter,
+    socket: UnixDatagram,
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut payload = Vec::new();
+        append_field(&mut payload, "MESSAGE", &record.args().to_string());
+        append_field(
+            &mut payload,
+            "PRIORITY",
+            &severity(record.level()).to_string(),
+        );
+        append_field(
+            &mut payload,
+            "SYSLOG_FACILITY",
+            &facility(record).to_string(),
+        );
+        append_field(&mut payload, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        append_field(&mut payload, "SYSLOG_PID", &process::id().to_string());
+        for (key, value) in audit::record_fields(record) {
+            let value = match value {
+                // Already sent in the standard fields
+                _ if matches!(key.as_str(), "timestamp" | "level" | "message" | "pid") => continue,
+                JsonValue::Null => continue,
+                JsonValue::String(value) => value,
+                value => value.to_string(),
+            };
+            let name = format!("SIBSECSH_{}", key.to_ascii_uppercase());
+            append_field(&mut payload, &name, &value);
+        }
+        self.socket.send(&payload).ok();
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for JournaldLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+/// Configuration of the human-readable loggers
+fn text_config() -> Config {
+    ConfigBuilder::new()
+        .set_time_offset_to_local()
+        // Just use UTC if simplelog can't determine the offset
+        .or_else::<&mut ConfigBuilder, _>(Ok)
+        .unwrap()
+        .set_time_format_rfc3339()
+        .set_target_level(LevelFilter::Error)
+        .build()
+}
+
+/// Create a logger for `target`, failing if it is unavailable
+pub fn open(configuration: &SecRcCfg, target: LogTarget) -> Result<Box<dyn SharedLogger>, Error> {
+    let format = configuration.log_format();
+    Ok(match target {
+        LogTarget::File => {
+            let log_file = configuration.open_log()?;
+            match format {
+                LogFormat::Text => WriteLogger::new(LevelFilter::Info, text_config(), log_file),
+                LogFormat::Json => Box::new(JsonLogger::new(LevelFilter::Info, log_file)),
+            }
+        }
+        LogTarget::Syslog => {
+            let path = configuration.syslog_socket();
+            let socket = connect(path).map_err(|e| Error::Syslog(path.to_string(), e))?;
+            Box::new(SyslogLogger {
+                level: LevelFilter::Info,
+                format,
+                socket,
+            })
+        }
+        LogTarget::Journald => Box::new(JournaldLogger {
+            level: LevelFilter::Info,
+            socket: connect(JOURNALD_SOCKET).map_err(Error::Journald)?,
+        }),
+    })
+}
+
+/// Log warnings to the terminal and everything to the configured target,
+/// or to the first available one of the others.
+/// Returns the problems with the targets to be logged.
+pub fn init(configuration: &SecRcCfg) -> Result<Vec<Error>, SetLoggerError> {
+    let preferred = configuration.log_target();
+    let mut problems = Vec::new();
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        LevelFilter::Warn,
+        text_config(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )];
+    let available = iter::once(preferred)
+        .chain(
+            FALLBACK_ORDER
+                .into_iter()
+                .filter(|&target| target != preferred),
+        )
+        .find_map(|target| match open(configuration, target) {
+            Ok(logger) => Some((target, logger)),
+            Err(e) => {
+                problems.push(e);
+                None
+            }
+        });
+    match available {
+        Some((target, logger)) => {
+            if target != preferred {
+                problems.push(Error::Fallback(target));
+            }
+            loggers.push(logger);
+        }
+        None => problems.push(Error::NoTarget),
+    }
+    CombinedLogger::init(loggers)?;
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// A syslog logger connected to a socket bound for the test
+    fn syslog(name: &str, format: LogFormat) -> (SyslogLogger, UnixDatagram) {
+        let path = env::temp_dir().join(format!("sibsecsh-test-{}-{name}.sock", process::id()));
+        fs::remove_file(&path).ok();
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let socket = connect(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).ok();
+        let logger = SyslogLogger {
+            level: LevelFilter::Info,
+            format,
+            socket,
+        };
+        (logger, daemon)
+    }
+
+    fn receive(daemon: &UnixDatagram) -> String {
+        let mut buffer = [0; 4096];
+        let len = daemon.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn syslog_text() {
+        let (logger, daemon) = syslog("text", LogFormat::Text);
+        logger.log(
+            &Record::builder()
+                .level(Level::Info)
+                .args(format_args!("Hello"))
+                .build(),
+        );
+        assert_eq!(
+            receive(&daemon),
+            format!("<14>sibsecsh[{}]: Hello", process::id())
+        );
+        let event = [("event", "factor_rejected")];
+        logger.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .args(format_args!("Authenticator \"totp\" rejected"))
+                .key_values(&event)
+                .build(),
+        );
+        assert_eq!(
+            receive(&daemon),
+            format!(
+                "<84>sibsecsh[{}]: Authenticator \"totp\" rejected",
+                process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn syslog_skips_disabled_levels() {
+        let (logger, daemon) = syslog("level", LogFormat::Text);
+        logger.log(
+            &Record::builder()
+                .level(Level::Debug)
+                .args(format_args!("Hidden"))
+                .build(),
+        );
+        daemon.set_nonblocking(true).unwrap();
+        let mut buffer = [0; 16];
+        assert!(daemon.recv(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn syslog_json() {
+        let (logger, daemon) = syslog("json", LogFormat::Json);
+        let event = [("event", "shell_exec")];
+        logger.log(
+            &Record::builder()
+                .level(Level::Info)
+                .args(format_args!("Executing shell"))
+                .key_values(&event)
+                .build(),
+        );
+        let line = receive(&daemon);
+        let prefix = format!("<86>sibsecsh[{}]: ", process::id());
+        let json: JsonValue = serde_json::from_str(line.strip_prefix(&prefix).unwrap()).unwrap();
+        assert_eq!(json["event"], "shell_exec");
+        assert_eq!(json["message"], "Executing shell");
+        assert_eq!(json["level"], "INFO");
+    }
+}
@@ -33,131 +33,153 @@
     variant_size_differences
 )]
 
+mod audit;
 mod auth;
+mod auth_backup;
 mod auth_email;
+mod auth_hotp;
 mod auth_totp;
 mod auth_yubico;
+mod chain;
+mod check_config;
 mod config;
 mod ip;
+mod lockout;
+mod logging;
 mod parse_args;
+mod secret;
+mod state;
+mod terminal;
 
+use crate::audit::Event;
 use crate::auth::Authenticator;
-use log::{info, warn};
-use simplelog::{
-    ColorChoice, CombinedLogger, ConfigBuilder, LevelFilter, TermLogger, TerminalMode, WriteLogger,
-};
+use crate::config::SecRcCfg;
+use crate::parse_args::Subcommand;
+use log::{Level, SetLoggerError, error, warn};
+use std::io::{self, IsTerminal};
+use std::panic;
+use std::process::ExitCode;
+use thiserror::Error;
 
-fn do_check_auth<'a>(
-    authenticator: &impl Authenticator<'a>,
-    configuration: &config::SecRcCfg,
-) -> Result<(), String> {
-    // First see whether -c is supplied
-    let options = parse_args::parse_args();
-    let mut other_options = options.1;
-    let mut exec_options: Vec<String> = Vec::new();
-    let is_accepted = options.0.map_or_else(
-        || authenticator.is_accepted_login(),
-        |mut cmd| {
-            let tmp = authenticator.is_accepted_exec(&mut cmd);
-            exec_options.push(String::from("-c"));
-            exec_options.push(cmd);
-            tmp
-        },
-    );
-    exec_options.append(&mut other_options);
-    match is_accepted {
-        Some(true) => configuration
-            .execute_shell(exec_options)
-            .map_err(|e| e.to_string()),
-        Some(false) => Err("Rejected".to_string()),
-        None => Ok(()),
-    }
+/// Exit code when access is denied or an administrative subcommand fails
+const EXIT_REJECTED: u8 = 1;
+/// Exit code when the configuration is unusable
+const EXIT_CONFIG: u8 = 2;
+/// Exit code when the shell cannot be executed, as in POSIX shells
+const EXIT_EXEC: u8 = 126;
+
+/// Why sibsecsh exits without executing the shell
+#[derive(Error, Debug)]
+enum Error {
+    #[error("Cannot load configuration: {0}")]
+    Config(#[from] config::Error),
+    #[error("Cannot create logger: {0}")]
+    Logger(#[from] SetLoggerError),
+    #[error("{0}")]
+    Chain(#[from] chain::Error),
+    #[error("Cannot enroll TOTP: {0}")]
+    TotpEnroll(#[from] auth_totp::Error),
+    #[error("Cannot generate backup codes: {0}")]
+    BackupCodes(#[from] auth_backup::Error),
 }
 
-#[expect(clippy::needless_pass_by_value)]
-fn print_err_exit(msg: String) -> Result<(), String> {
-    panic!("Sorry: {msg}");
+impl Error {
+    /// The process exit code for this class of failure
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_)
+            | Self::Logger(_)
+            | Self::Chain(chain::Error::UnknownAuthenticator(_) | chain::Error::LockoutState(_)) => {
+                EXIT_CONFIG
+            }
+            Self::Chain(chain::Error::Exec(config::Error::ShellExec(_))) => EXIT_EXEC,
+            // No usable shell configured
+            Self::Chain(chain::Error::Exec(_)) => EXIT_CONFIG,
+            Self::Chain(_) | Self::TotpEnroll(_) | Self::BackupCodes(_) => EXIT_REJECTED,
+        }
+    }
 }
 
-fn main() {
-    // Wait for user input before panic!king.
-    std::panic::set_hook(Box::new(|panic_info| {
-        if let Some(location) = panic_info.location() {
-            eprint!("Panic occurred at {}:{}", location.file(), location.line());
-        } else {
-            eprint!("Panic occurred");
+/// Load the configuration, set up logging and do what is asked
+fn run(configuration: &mut SecRcCfg, subcommand: Option<Subcommand>) -> Result<(), Error> {
+    let load_result = configuration.load_all_possible();
+    let username = whoami::username().unwrap_or_else(|_| "<failed to get username>".to_string());
+    let from_ip = ip::get_from();
+    audit::start_session(&username, &from_ip);
+    secret::set_reveal(configuration.log_secrets());
+    for problem in logging::init(configuration)? {
+        warn!("{problem}");
+    }
+
+    match load_result {
+        Ok(warnings) => {
+            for warning in warnings {
+                if let config::Error::MissingConfig = warning {
+                    warn!("No configuration supplied!");
+                } else {
+                    warn!("{warning}");
+                }
+            }
         }
+        Err(e) => {
+            error!("Cannot load configuration: {e}");
+            return Err(e.into());
+        }
+    }
+    match subcommand {
+        Some(Subcommand::TotpEnroll) => {
+            auth_totp::TotpAuthenticator::init(configuration).enroll()?;
+        }
+        Some(Subcommand::BackupCodesGenerate) => {
+            auth_backup::BackupCodeAuthenticator::init(configuration).generate()?;
+        }
+        // Handled before the logger is set up
+        Some(Subcommand::CheckConfig) => (),
+        None => {
+            audit::event(
+                Level::Info,
+                Event::AttemptStarted,
+                None,
+                format_args!("Login attempt from {from_ip} for {username}"),
+            );
+            chain::run(configuration)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    // Only reached on bugs, and the source location means nothing to users
+    panic::set_hook(Box::new(|panic_info| {
         if let Some(msg) = panic_info.payload().downcast_ref::<&str>() {
-            eprintln!(": {msg}");
+            eprintln!("Sorry, an internal error occurred: {msg}");
         } else if let Some(msg) = panic_info.payload().downcast_ref::<String>() {
-            eprintln!(": {msg}");
+            eprintln!("Sorry, an internal error occurred: {msg}");
         } else {
-            eprintln!();
+            eprintln!("Sorry, an internal error occurred");
         }
-        std::io::stdin().read_line(&mut String::new()).unwrap();
     }));
 
-    let mut configuration = config::SecRcCfg::default();
-    let load_result = configuration.load_all_possible();
-    let log_file = match configuration.open_log() {
-        Ok(f) => f,
-        Err(e) => panic!("Cannot open log file: {e}"),
-    };
-    let log_format = ConfigBuilder::new()
-        .set_time_offset_to_local()
-        // Just use UTC if simplelog can't determine the offset
-        .or_else::<&mut ConfigBuilder, _>(Ok)
-        .unwrap()
-        .set_time_format_rfc3339()
-        .set_target_level(LevelFilter::Error)
-        .build();
-
-    if let Err(e) = CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Warn,
-            log_format.clone(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(LevelFilter::Info, log_format, log_file),
-    ]) {
-        panic!("Cannot create logger: {e}");
+    let (subcommand, user_file) = parse_args::parse_subcommand().unzip();
+    let user_file = user_file.flatten();
+    // Must not depend on a usable log file
+    if subcommand == Some(Subcommand::CheckConfig) {
+        return ExitCode::from(u8::from(!check_config::check_config(user_file)));
     }
 
-    if load_result.is_err() {
-        warn!("No configuration supplied!");
+    let mut configuration = SecRcCfg::default();
+    configuration.set_user_file(user_file);
+    match run(&mut configuration, subcommand) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Sorry: {e}");
+            // Let users of web terminals read the message before the
+            // session closes, but never block scp, rsync and the like
+            if configuration.pause_on_error() && io::stdin().is_terminal() {
+                eprint!("Press Enter to exit");
+                io::stdin().read_line(&mut String::new()).ok();
+            }
+            ExitCode::from(e.exit_code())
+        }
     }
-    let username = whoami::username().unwrap_or_else(|_| "<failed to get username>".to_string());
-    info!("Login attempt from {} for {}", ip::get_from(), username);
-    do_check_auth(
-        &auth::BypassAuthenticator::init(&configuration),
-        &configuration,
-    )
-    .or_else(print_err_exit)
-    .ok();
-    do_check_auth(
-        &auth::LocalIPAuthenticator::init(&configuration),
-        &configuration,
-    )
-    .or_else(print_err_exit)
-    .ok();
-    do_check_auth(
-        &auth_email::EmailAuthenticator::init(&configuration),
-        &configuration,
-    )
-    .or_else(print_err_exit)
-    .ok();
-    do_check_auth(
-        &auth_totp::TotpAuthenticator::init(&configuration),
-        &configuration,
-    )
-    .or_else(print_err_exit)
-    .ok();
-    do_check_auth(
-        &auth_yubico::YubicoAuthenticator::init(&configuration),
-        &configuration,
-    )
-    .or_else(print_err_exit)
-    .ok();
-    panic!("Sorry: All authenticators skipped");
 }
@@ -17,21 +17,193 @@
 //  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
 //
 
-use log::info;
+use crate::secret::Redacted;
+use log::{info, warn};
 use std::env;
+use std::path::PathBuf;
 
-/// Parse command line arguments, the first reture value is the aargument to
-/// -c if any, and the second argument is the other arguments
-pub fn parse_args() -> (Option<String>, Vec<String>) {
-    let all_args: Vec<String> = env::args().collect();
-    info!("Program arguments: {all_args:?}");
-    for (i, argument) in env::args().enumerate() {
-        if argument == "-c" {
-            let mut other: Vec<String> = all_args[1..i].to_vec();
-            let mut at_the_back = all_args[i + 2..all_args.len()].to_vec();
-            other.append(&mut at_the_back);
-            return (Some(all_args[i + 1].clone()), other);
+/// A code that an authenticator may take from the start of the argument
+/// to -c
+#[derive(Debug, Clone, Copy)]
+pub struct CodeFormat {
+    /// Length in bytes
+    pub len: usize,
+    /// Whether a byte can be part of the code
+    pub is_code_byte: fn(&u8) -> bool,
+}
+
+/// Show the argument to -c with the codes it may start with redacted, each
+/// of `formats` taking its code in turn like the chain does
+fn redact_codes(command: &str, formats: &[CodeFormat]) -> String {
+    let mut len = 0;
+    for format in formats {
+        let is_code = command
+            .as_bytes()
+            .get(len..len + format.len)
+            .is_some_and(|code| code.iter().all(format.is_code_byte));
+        if is_code {
+            len += format.len;
         }
     }
-    (None, all_args[1..all_args.len()].to_vec())
+    // Codes are ASCII, so this is a character boundary
+    let (codes, command) = command.split_at(len);
+    if codes.is_empty() {
+        format!("{command:?}")
+    } else {
+        format!("{:?}{command:?}", Redacted(codes))
+    }
+}
+
+/// Parse command line arguments, the first reture value is the aargument to
+/// -c if any, and the second argument is the other arguments.
+/// `formats` are the codes to keep out of the log.
+pub fn parse_args(formats: &[CodeFormat]) -> (Option<String>, Vec<String>) {
+    split_args(&env::args().collect::<Vec<_>>(), formats)
+}
+
+/// Like `parse_args`, with `all_args` being the program arguments
+fn split_args(all_args: &[String], formats: &[CodeFormat]) -> (Option<String>, Vec<String>) {
+    let Some(i) = all_args.iter().position(|argument| argument == "-c") else {
+        info!("Program arguments: {all_args:?}");
+        return (None, all_args.get(1..).unwrap_or_default().to_vec());
+    };
+    let other = all_args.get(1..i).unwrap_or_default().iter();
+    let Some(command) = all_args.get(i + 1) else {
+        // Nothing to run, so treat it like a login
+        warn!("Missing argument to -c in {all_args:?}");
+        return (None, other.cloned().collect());
+    };
+    let at_the_back = &all_args[i + 2..];
+    info!(
+        "Program arguments: {:?} -c {} {at_the_back:?}",
+        &all_args[..i],
+        redact_codes(command, formats),
+    );
+    (
+        Some(command.clone()),
+        other.chain(at_the_back).cloned().collect(),
+    )
+}
+
+/// Administrative subcommands, only recognized as the very first arguments
+/// so that they never collide with a login shell invocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    /// `totp enroll`
+    TotpEnroll,
+    /// `backup-codes generate`
+    BackupCodesGenerate,
+    /// `check-config`
+    CheckConfig,
+}
+
+/// Parse the subcommand if the program is not invoked as a shell, along
+/// with the file given by a leading `--config FILE`, or else by
+/// `$SIBSECSH_CONFIG`, to use instead of the user files.
+/// Logins never look at `$SIBSECSH_CONFIG`, which anyone who can set the
+/// environment of the login could otherwise use to replace the user files.
+pub fn parse_subcommand() -> Option<(Subcommand, Option<PathBuf>)> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (user_file, args) = match args.as_slice() {
+        ["--config", user_file, rest @ ..] => (Some(PathBuf::from(user_file)), rest),
+        rest => (None, rest),
+    };
+    let subcommand = match args {
+        ["totp", "enroll"] => Subcommand::TotpEnroll,
+        ["backup-codes", "generate"] => Subcommand::BackupCodesGenerate,
+        ["check-config"] => Subcommand::CheckConfig,
+        _ => return None,
+    };
+    let user_file = user_file.or_else(|| {
+        env::var_os("SIBSECSH_CONFIG")
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    });
+    Some((subcommand, user_file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodeFormat, redact_codes, split_args};
+
+    const TOTP: CodeFormat = CodeFormat {
+        len: 6,
+        is_code_byte: u8::is_ascii_digit,
+    };
+    const BACKUP: CodeFormat = CodeFormat {
+        len: 10,
+        is_code_byte: |byte| byte.is_ascii_lowercase() || byte.is_ascii_digit(),
+    };
+
+    #[test]
+    fn redacts_only_codes() {
+        assert_eq!(
+            redact_codes("123456ls -la", &[TOTP]),
+            r#"<redacted>"ls -la""#
+        );
+        assert_eq!(redact_codes("123456", &[TOTP]), r#"<redacted>"""#);
+        assert_eq!(
+            redact_codes("123456abcde12345 ls", &[TOTP, BACKUP]),
+            r#"<redacted>" ls""#
+        );
+    }
+
+    #[test]
+    fn skips_absent_codes() {
+        // Like the chain, a missing code leaves the next one at the start
+        assert_eq!(
+            redact_codes("abcde12345ls", &[TOTP, BACKUP]),
+            r#"<redacted>"ls""#
+        );
+        assert_eq!(
+            redact_codes("123456 ls", &[BACKUP, TOTP]),
+            r#"<redacted>" ls""#
+        );
+    }
+
+    #[test]
+    fn keeps_commands_without_codes() {
+        assert_eq!(
+            redact_codes("rsync --server", &[TOTP, BACKUP]),
+            r#""rsync --server""#
+        );
+        assert_eq!(redact_codes("uptime", &[TOTP, BACKUP]), r#""uptime""#);
+        assert_eq!(redact_codes("12345 ls", &[TOTP]), r#""12345 ls""#);
+        assert_eq!(redact_codes("123456ls", &[]), r#""123456ls""#);
+        assert_eq!(redact_codes("", &[TOTP]), r#""""#);
+    }
+
+    #[test]
+    fn stops_at_non_ascii() {
+        assert_eq!(redact_codes("12é456", &[TOTP]), r#""12é456""#);
+        assert_eq!(redact_codes("123456é", &[TOTP, BACKUP]), r#"<redacted>"é""#);
+    }
+
+    fn split(args: &[&str]) -> (Option<String>, Vec<String>) {
+        let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+        split_args(&args, &[TOTP])
+    }
+
+    #[test]
+    fn splits_command() {
+        assert_eq!(
+            split(&["sibsecsh", "-l", "-c", "123456ls", "x"]),
+            (
+                Some(String::from("123456ls")),
+                vec![String::from("-l"), String::from("x")]
+            )
+        );
+        assert_eq!(split(&["sibsecsh", "-l"]), (None, vec![String::from("-l")]));
+        assert_eq!(split(&[]), (None, vec![]));
+    }
+
+    #[test]
+    fn trailing_c_is_a_login() {
+        assert_eq!(split(&["sibsecsh", "-c"]), (None, vec![]));
+        assert_eq!(
+            split(&["sibsecsh", "-l", "-c"]),
+            (None, vec![String::from("-l")])
+        );
+    }
 }
@@ -0,0 +1,177 @@
+//
+//  Copyright (C) 2021 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use crate::config::{self, FileKind, SecRcCfg};
+use log::warn;
+use nix::fcntl::OFlag;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Error type for the persistent state
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("cannot parse state: {0}")]
+    TomlParse(#[from] toml::de::Error),
+    #[error("cannot serialize state: {0}")]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[error("refusing state directory {0}: {1}")]
+    InsecureDir(PathBuf, config::Error),
+}
+
+/// Options for opening a file in the state directory, never through a
+/// symbolic link that another user may have planted
+fn open_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.mode(0o600).custom_flags(OFlag::O_NOFOLLOW.bits());
+    options
+}
+
+/// A TOML state file, exclusively locked for as long as this value lives
+/// so that concurrent logins see each other's updates
+#[derive(Debug)]
+pub struct LockedState {
+    path: PathBuf,
+    // The lock is released when the file is closed
+    _lock: File,
+}
+
+impl LockedState {
+    /// Lock the state file `name` under `dir`, creating `dir` if needed.
+    /// `dir` is checked to be `kind` according to `strict_modes`, as anyone
+    /// else able to write into it could tamper with the state.
+    pub fn open(config: &SecRcCfg, dir: &Path, name: &str, kind: FileKind) -> Result<Self, Error> {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        // Checking the directory itself rather than a link to it
+        let dir = fs::canonicalize(dir)?;
+        let insecure = |e| Error::InsecureDir(dir.clone(), e);
+        if let Some(warning) = config
+            .check_modes(&fs::metadata(&dir)?, kind)
+            .map_err(insecure)?
+        {
+            warn!("State directory {}: {warning}", dir.display());
+        }
+        let path = dir.join(name);
+        let lock = open_options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        lock.lock()?;
+        Ok(Self { path, _lock: lock })
+    }
+
+    /// Lock the state file `name` under `state_dir`, which must not be
+    /// accessible by anyone else
+    pub fn open_state(config: &SecRcCfg, name: &str) -> Result<Self, Error> {
+        Self::open(config, &config.state_path(), name, FileKind::Secret)
+    }
+
+    /// Read the state, a missing file being the default state
+    pub fn read<T: DeserializeOwned + Default>(&self) -> Result<T, Error> {
+        match open_options().read(true).open(&self.path) {
+            Ok(mut file) => {
+                let mut content = String::new();
+                file.read_to_string(&mut content)?;
+                Ok(toml::from_str(&content)?)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically replace the state
+    pub fn write<T: Serialize>(&self, state: &T) -> Result<(), Error> {
+        let content = toml::to_string(state)?;
+        let tmp_path = self.path.with_extension("tmp");
+        // A leftover file is removed rather than followed or reused
+        match fs::remove_file(&tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        let mut file = open_options()
+            .create_new(true)
+            .write(true)
+            .open(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    use std::{env, process};
+
+    fn test_dir(name: &str) -> (SecRcCfg, PathBuf) {
+        let dir = env::temp_dir().join(format!("sibsecsh-test-{}-{name}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut config = SecRcCfg::default();
+        config.tmpdir = Some(dir.display().to_string());
+        (config, dir)
+    }
+
+    #[test]
+    fn refuses_shared_dir() {
+        let (config, dir) = test_dir("state-shared");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        let result = LockedState::open_state(&config, "test.toml");
+        fs::remove_dir_all(&dir).ok();
+        assert!(matches!(result, Err(Error::InsecureDir(..))));
+    }
+
+    #[test]
+    fn refuses_linked_lock() {
+        let (config, dir) = test_dir("state-lock");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        symlink(dir.join("elsewhere"), dir.join("test.lock")).unwrap();
+        let result = LockedState::open_state(&config, "test.toml");
+        let planted = dir.join("elsewhere").exists();
+        fs::remove_dir_all(&dir).ok();
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert!(!planted);
+    }
+
+    #[test]
+    fn write_replaces_linked_tmp() {
+        let (config, dir) = test_dir("state-tmp");
+        let state = LockedState::open_state(&config, "test.toml").unwrap();
+        fs::write(dir.join("victim"), "untouched").unwrap();
+        symlink(dir.join("victim"), dir.join("test.tmp")).unwrap();
+        let written: BTreeMap<String, u32> = BTreeMap::from([(String::from("counter"), 1)]);
+        state.write(&written).unwrap();
+        let read: BTreeMap<String, u32> = state.read().unwrap();
+        let victim = fs::read_to_string(dir.join("victim")).unwrap();
+        fs::remove_dir_all(&dir).ok();
+        assert_eq!(read, written);
+        assert_eq!(victim, "untouched");
+    }
+}
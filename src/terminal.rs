@@ -0,0 +1,54 @@
+//
+//  Copyright (C) 2026 Zhang Maiyun <me@maiyun.me>
+//
+//  This file is part of sib secure shell.
+//
+//  Sib secure shell is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  Sib secure shell is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with sib secure shell.  If not, see <https://www.gnu.org/licenses/>.
+//
+
+use nix::sys::termios::{self, LocalFlags, SetArg, Termios};
+use std::io::{self, Write, stdin, stdout};
+
+/// Restores the terminal settings when dropped
+struct EchoGuard {
+    original: Termios,
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        termios::tcsetattr(stdin(), SetArg::TCSANOW, &self.original).ok();
+    }
+}
+
+/// Stop echoing the input if stdin is a terminal
+fn hide_input() -> Option<EchoGuard> {
+    let original = termios::tcgetattr(stdin()).ok()?;
+    let mut hidden = original.clone();
+    hidden.local_flags.remove(LocalFlags::ECHO);
+    // Still move to the next line when Enter is pressed
+    hidden.local_flags.insert(LocalFlags::ECHONL);
+    termios::tcsetattr(stdin(), SetArg::TCSANOW, &hidden).ok()?;
+    Some(EchoGuard { original })
+}
+
+/// Show `prompt` and read a line, without echoing it if stdin is a
+/// terminal. The line is returned as `read_line` gives it.
+pub fn read_hidden(prompt: &str) -> io::Result<String> {
+    print!("{prompt}");
+    stdout().flush().ok();
+    let _guard = hide_input();
+    let mut input = String::new();
+    stdin().read_line(&mut input)?;
+    Ok(input)
+}